@@ -1,23 +1,92 @@
+// near_bindgen generates an external function per method, so callbacks that carry the
+// swap state trip this lint outside of any item we could annotate.
+#![allow(clippy::too_many_arguments)]
+
 use near_contract_standards::fungible_token::core::ext_ft_core::ext as ft_core_ext;
 use near_contract_standards::fungible_token::metadata::ext_ft_metadata::ext as ft_metadata_ext;
 use near_contract_standards::fungible_token::metadata::FungibleTokenMetadata;
 use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
+use near_contract_standards::storage_management::StorageBalance;
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
-use near_sdk::json_types::U128;
+use near_sdk::collections::{LookupMap, UnorderedSet};
+use near_sdk::json_types::{U128, U64};
 use near_sdk::serde::{Deserialize, Serialize};
 use near_sdk::{
-    env, log, near_bindgen, require, AccountId, Balance, Gas, PanicOnDefault, Promise,
-    PromiseError, PromiseOrValue,
+    env, ext_contract, log, near_bindgen, require, AccountId, Balance, BorshStorageKey, Gas,
+    PanicOnDefault, Promise, PromiseError, PromiseOrValue, PromiseResult,
 };
 use uint::construct_uint;
 
+// The NEP-145 view used to probe tokens, near-contract-standards has no ext for it
+#[ext_contract(ext_storage)]
+pub trait StorageBalanceOf {
+    fn storage_balance_of(&self, account_id: AccountId) -> Option<StorageBalance>;
+}
+
 const TGAS: Gas = Gas(10_000_000_000_000);
+// NEP-141 transfers require exactly one yoctoNEAR attached
+pub const ONE_YOCTO: Balance = 1;
+// NEAR kept free of storage staking for transfer deposits and new state (0.1 NEAR)
+const MIN_AVAILABLE_NEAR: Balance = 100_000_000_000_000_000_000_000;
+const DEFAULT_FEE_BPS: u16 = 30;
+// How long a fetched ft_balance_of result is reused, in nanoseconds (1 minute)
+const BALANCE_CACHE_TTL: u64 = 60_000_000_000;
+const MAX_FEE_BPS: u16 = 1000;
+// Largest cut of the swap output an integrator can send to its platform account
+const MAX_PLATFORM_FEE_BPS: u16 = 500;
+// ft_transfer_call needs gas for the receiver's ft_on_transfer and the token's resolve step
+const FT_TRANSFER_CALL_GAS: Gas = Gas(50_000_000_000_000);
+// Covers the transfer of the unused output and its resolve callback, TGAS each
+const FORWARD_CALLBACK_GAS: Gas = Gas(30_000_000_000_000);
+// Covers the storage_balance_of check of a failed output transfer
+const SWAP_CALLBACK_GAS: Gas = Gas(20_000_000_000_000);
+// For the storage_balance_of view and its callback each
+const TRANSFER_FAILURE_CHECK_GAS: Gas = Gas(5_000_000_000_000);
+const BPS_DENOMINATOR: u128 = 10_000;
+const PRICE_PRECISION: u128 = 1_000_000_000_000_000_000;
+const MAX_SIMULATED_SWAPS: usize = 50;
+const MAX_METADATA_QUERIES: usize = 50;
+// Largest deviation from the pool ratio accepted by add_proportional
+const PROPORTIONAL_TOLERANCE_BPS: u128 = 10;
+// Number of recent swaps kept for sandwich detection
+const SANDWICH_WINDOW: usize = 3;
+// Number of fee changes kept in the history
+const FEE_HISTORY_SIZE: usize = 20;
+// Number of emitted events kept in contract storage
+const RECENT_EVENTS_SIZE: u64 = 50;
+const MAX_FEE_DISCOUNT_TIERS: usize = 10;
+// Shares locked by the first mint and held by no account, like in Uniswap V2. They keep the
+// share price from being inflated by donations to a nearly empty pool.
+pub const MINIMUM_LIQUIDITY: Balance = 1_000;
+
+#[derive(BorshSerialize, BorshStorageKey)]
+enum StorageKey {
+    // Emptying the pool starts a new share map, see `emergency_withdraw`
+    Shares { epoch: u32 },
+    PendingDeposits,
+    RecentEvents,
+    Volume,
+    AllowedSwappers,
+}
 
 #[derive(BorshDeserialize, BorshSerialize, PanicOnDefault)]
 struct Token {
     address: AccountId,
     balance: Balance,
     metadata: Option<TokenMetadata>,
+    // Largest amount accepted from a single transfer, the rest gets refunded
+    max_single_deposit: Option<Balance>,
+    // Block height of the last metadata update
+    last_metadata_block: u64,
+    // A refresh started by a swap is in flight, so later swaps do not start another one
+    metadata_refresh_pending: bool,
+    // Last ft_balance_of result of the AMM and its block timestamp, cleared on reserve changes
+    // and outgoing transfers
+    cached_balance: Option<(Balance, u64)>,
+    // Output transfers of this token that failed in a row
+    transfer_failures: u32,
+    // Swaps into this token are rejected until the owner resumes them
+    output_paused: bool,
 }
 
 impl Token {
@@ -26,10 +95,23 @@ impl Token {
             address,
             balance: 0,
             metadata: None,
+            max_single_deposit: None,
+            last_metadata_block: 0,
+            metadata_refresh_pending: false,
+            cached_balance: None,
+            transfer_failures: 0,
+            output_paused: false,
         }
     }
 }
 
+#[derive(BorshDeserialize, BorshSerialize)]
+struct RecentSwap {
+    block_height: u64,
+    sender_id: AccountId,
+    token_in: usize,
+}
+
 #[derive(BorshDeserialize, BorshSerialize, Deserialize, Serialize, Clone, PanicOnDefault)]
 pub struct TokenMetadata {
     name: String,
@@ -47,26 +129,525 @@ impl TokenMetadata {
     }
 }
 
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct MetadataStatus {
+    initialized: bool,
+    last_update_block: u64,
+    // Older than the metadata TTL, a refresh is triggered by the next swap
+    stale: bool,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct TokenState {
+    address: AccountId,
+    reserve: U128,
+    metadata: Option<TokenMetadata>,
+    max_single_deposit: Option<U128>,
+    output_paused: bool,
+}
+
+// Snapshot of the pool state and settings for backups and audits
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PoolState {
+    owner: AccountId,
+    pending_owner: Option<AccountId>,
+    tokens: Vec<TokenState>,
+    fee_bps: u16,
+    protocol_fee_bps: u16,
+    swaps_paused: bool,
+    paused: bool,
+    has_swapped: bool,
+    owner_can_swap: bool,
+    max_init_imbalance_bps: Option<U128>,
+    max_drift_bps: Option<u16>,
+    min_execution_rate: Option<U128>,
+    large_swap_threshold: Option<U128>,
+    metadata_ttl_blocks: Option<u64>,
+    sandwich_detection: bool,
+    strict_init: bool,
+    max_transfer_failures: Option<u32>,
+    total_shares: U128,
+}
+
+// Everything a pool card needs in one view call
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct MarketOverview {
+    reserve_a: U128,
+    reserve_b: U128,
+    // Spot price of token A in token B, see `get_spot_price`
+    mid_price: U128,
+    // Output for swapping the reference amount in each direction
+    amount_out_a_to_b: U128,
+    amount_out_b_to_a: U128,
+    fee_bps: u16,
+}
+
+// Pool composition in one view call
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PoolInfo {
+    owner: AccountId,
+    token_a: AccountId,
+    token_b: AccountId,
+    metadata_a: Option<TokenMetadata>,
+    metadata_b: Option<TokenMetadata>,
+    reserve_a: U128,
+    reserve_b: U128,
+}
+
+// Pool state for dashboards in one view call
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Snapshot {
+    reserve_a: U128,
+    reserve_b: U128,
+    ratio: U128,
+    // Decimals-adjusted, scaled by 10^18
+    price_a_in_b: U128,
+    price_b_in_a: U128,
+    fee_bps: u16,
+    paused: bool,
+    swaps_paused: bool,
+    total_shares: U128,
+}
+
+#[derive(Deserialize, PartialEq)]
+#[serde(crate = "near_sdk::serde", rename_all = "snake_case")]
+enum TransferAction {
+    Swap,
+    AddLiquidity,
+}
+
+// Options a swapper can pass as JSON in the `ft_transfer_call` msg
+#[derive(Deserialize, Default)]
+#[serde(crate = "near_sdk::serde")]
+struct SwapMsg {
+    // `add_liquidity` holds the transfer as a pending deposit instead of swapping it
+    action: Option<TransferAction>,
+    // Smallest acceptable amount of output tokens
+    min_amount_out: Option<U128>,
+    // Largest acceptable shortfall against the current quote, which includes the pool fee
+    max_slippage_bps: Option<u16>,
+    // Largest acceptable loss against the spot value of the input. The spot value excludes the
+    // fee, so this bounds fees and price impact together.
+    max_value_loss_bps: Option<u16>,
+    // Deliver the output with `ft_transfer_call` to this account instead of the sender
+    forward_to: Option<AccountId>,
+    forward_msg: Option<String>,
+    // Share of the output sent to `platform_account`, the slippage bounds apply before it
+    platform_fee_bps: Option<u16>,
+    platform_account: Option<AccountId>,
+    // Latest block timestamp, in nanoseconds, at which the swap may execute
+    deadline: Option<U64>,
+    // Largest acceptable shortfall against the spot price after the fee, i.e. price impact only
+    max_impact_bps: Option<u16>,
+    // Largest acceptable fee plus price impact, both measured against the mid price
+    max_total_cost_bps: Option<u16>,
+}
+
+impl SwapMsg {
+    fn parse(msg: &str) -> Self {
+        if msg.is_empty() {
+            return Self::default();
+        }
+        let swap_msg = near_sdk::serde_json::from_str(msg);
+        require!(swap_msg.is_ok(), "Invalid swap msg.");
+        swap_msg.unwrap()
+    }
+}
+
 // Create U256 to avoid overflows in swap calculations
-construct_uint! {
-    struct U256(4);
+#[allow(clippy::assign_op_pattern, clippy::manual_div_ceil)]
+mod u256 {
+    use super::construct_uint;
+
+    construct_uint! {
+        pub struct U256(4);
+    }
+}
+use u256::U256;
+
+// NEP-297 events emitted by the AMM
+mod events {
+    use near_sdk::json_types::U128;
+    use near_sdk::serde::Serialize;
+    use near_sdk::{env, serde_json, AccountId};
+
+    const EVENT_STANDARD: &str = "amm";
+    const EVENT_STANDARD_VERSION: &str = "1.0.0";
+
+    #[derive(Serialize)]
+    #[serde(crate = "near_sdk::serde")]
+    struct EventLog<'a, T: Serialize> {
+        standard: &'a str,
+        version: &'a str,
+        event: &'a str,
+        data: [T; 1],
+    }
+
+    // Returns the event JSON, so the contract can also keep it on-chain
+    fn log_event<T: Serialize>(event: &str, data: T) -> String {
+        let event_log = EventLog {
+            standard: EVENT_STANDARD,
+            version: EVENT_STANDARD_VERSION,
+            event,
+            data: [data],
+        };
+        let event_json = serde_json::to_string(&event_log).unwrap();
+        env::log_str(&format!("EVENT_JSON:{}", event_json));
+        event_json
+    }
+
+    #[derive(Serialize)]
+    #[serde(crate = "near_sdk::serde")]
+    struct PoolCreated<'a> {
+        owner: &'a AccountId,
+        token_a: &'a AccountId,
+        token_b: &'a AccountId,
+        fee_bps: u16,
+    }
+
+    #[derive(Serialize)]
+    #[serde(crate = "near_sdk::serde")]
+    struct PossibleSandwich<'a> {
+        block_height: u64,
+        attacker: &'a AccountId,
+        victim: &'a AccountId,
+    }
+
+    pub fn log_possible_sandwich(
+        block_height: u64,
+        attacker: &AccountId,
+        victim: &AccountId,
+    ) -> String {
+        log_event(
+            "possible_sandwich",
+            PossibleSandwich {
+                block_height,
+                attacker,
+                victim,
+            },
+        )
+    }
+
+    #[derive(Serialize)]
+    #[serde(crate = "near_sdk::serde")]
+    struct LargeSwap<'a> {
+        sender_id: &'a AccountId,
+        token_in: &'a AccountId,
+        amount_in: U128,
+        amount_out: U128,
+    }
+
+    pub fn log_large_swap(
+        sender_id: &AccountId,
+        token_in: &AccountId,
+        amount_in: u128,
+        amount_out: u128,
+    ) -> String {
+        log_event(
+            "large_swap",
+            LargeSwap {
+                sender_id,
+                token_in,
+                amount_in: U128(amount_in),
+                amount_out: U128(amount_out),
+            },
+        )
+    }
+
+    #[derive(Serialize)]
+    #[serde(crate = "near_sdk::serde")]
+    struct Swap<'a> {
+        sender_id: &'a AccountId,
+        token_in: &'a AccountId,
+        token_out: &'a AccountId,
+        amount_in: U128,
+        amount_out: U128,
+    }
+
+    pub fn log_swap(
+        sender_id: &AccountId,
+        token_in: &AccountId,
+        token_out: &AccountId,
+        amount_in: u128,
+        amount_out: u128,
+    ) -> String {
+        log_event(
+            "swap",
+            Swap {
+                sender_id,
+                token_in,
+                token_out,
+                amount_in: U128(amount_in),
+                amount_out: U128(amount_out),
+            },
+        )
+    }
+
+    #[derive(Serialize)]
+    #[serde(crate = "near_sdk::serde")]
+    struct Deposit<'a> {
+        token: &'a AccountId,
+        amount: U128,
+    }
+
+    pub fn log_deposit(token: &AccountId, amount: u128) -> String {
+        log_event(
+            "deposit",
+            Deposit {
+                token,
+                amount: U128(amount),
+            },
+        )
+    }
+
+    #[derive(Serialize)]
+    #[serde(crate = "near_sdk::serde")]
+    struct AddLiquidity<'a> {
+        account_id: &'a AccountId,
+        amount_a: U128,
+        amount_b: U128,
+        shares: U128,
+    }
+
+    pub fn log_add_liquidity(
+        account_id: &AccountId,
+        amount_a: u128,
+        amount_b: u128,
+        shares: u128,
+    ) -> String {
+        log_event(
+            "add_liquidity",
+            AddLiquidity {
+                account_id,
+                amount_a: U128(amount_a),
+                amount_b: U128(amount_b),
+                shares: U128(shares),
+            },
+        )
+    }
+
+    #[derive(Serialize)]
+    #[serde(crate = "near_sdk::serde")]
+    struct RemoveLiquidity<'a> {
+        account_id: &'a AccountId,
+        amount_a: U128,
+        amount_b: U128,
+        shares: U128,
+    }
+
+    pub fn log_remove_liquidity(
+        account_id: &AccountId,
+        amount_a: u128,
+        amount_b: u128,
+        shares: u128,
+    ) -> String {
+        log_event(
+            "remove_liquidity",
+            RemoveLiquidity {
+                account_id,
+                amount_a: U128(amount_a),
+                amount_b: U128(amount_b),
+                shares: U128(shares),
+            },
+        )
+    }
+
+    #[derive(Serialize)]
+    #[serde(crate = "near_sdk::serde")]
+    struct OutputPaused<'a> {
+        token: &'a AccountId,
+        failures: u32,
+    }
+
+    pub fn log_output_paused(token: &AccountId, failures: u32) -> String {
+        log_event("output_paused", OutputPaused { token, failures })
+    }
+
+    #[derive(Serialize)]
+    #[serde(crate = "near_sdk::serde")]
+    struct FeeChanged {
+        old_fee_bps: u16,
+        new_fee_bps: u16,
+        timestamp: u64,
+    }
+
+    pub fn log_fee_changed(old_fee_bps: u16, new_fee_bps: u16, timestamp: u64) -> String {
+        log_event(
+            "fee_changed",
+            FeeChanged {
+                old_fee_bps,
+                new_fee_bps,
+                timestamp,
+            },
+        )
+    }
+
+    pub fn log_pool_created(
+        owner: &AccountId,
+        token_a: &AccountId,
+        token_b: &AccountId,
+        fee_bps: u16,
+    ) -> String {
+        log_event(
+            "pool_created",
+            PoolCreated {
+                owner,
+                token_a,
+                token_b,
+                fee_bps,
+            },
+        )
+    }
 }
 
 #[near_bindgen]
 #[derive(BorshDeserialize, BorshSerialize, PanicOnDefault)]
 pub struct AMM {
     owner: AccountId,
+    // Proposed by the owner, becomes the owner once it accepts
+    pending_owner: Option<AccountId>,
     tokens: Vec<Token>,
+    // Largest allowed reserve imbalance (larger / smaller, decimal-adjusted) in basis points,
+    // only enforced on owner deposits made before the first swap.
+    max_init_imbalance_bps: Option<u128>,
+    has_swapped: bool,
+    // Route owner transfers with a swap msg through the swap path instead of depositing them
+    owner_can_swap: bool,
+    // Largest shortfall of the actual balances against the tracked reserves, in basis points,
+    // that `reconcile` accepts before pausing swaps.
+    max_drift_bps: Option<u16>,
+    swaps_paused: bool,
+    // Emergency switch set by the owner, swaps are refunded while owner deposits still work
+    paused: bool,
+    // Blocks without an owner call after which the next swap pauses the pool, so an
+    // abandoned deployment stops trading
+    inactivity_pause_blocks: Option<u64>,
+    last_owner_action_block: u64,
+    // Observability only, flagged sandwiches are never blocked
+    sandwich_detection: bool,
+    recent_swaps: Vec<RecentSwap>,
+    // Swaps with a larger input emit a large_swap event. Whole tokens scaled by 10^18, so
+    // one threshold applies to both tokens regardless of their decimals.
+    large_swap_threshold: Option<u128>,
+    metadata_ttl_blocks: Option<u64>,
+    // Smallest accepted amount_out / amount_in in whole tokens, scaled by 10^18. Guards
+    // swappers against pathologically imbalanced reserves in both directions.
+    min_execution_rate: Option<u128>,
+    // Swap fee taken from the input. It stays in the reserves, so K grows with every swap.
+    fee_bps: u16,
+    // (block timestamp, new fee) of the latest fee changes, oldest first
+    fee_history: Vec<(u64, u16)>,
+    // Share of the swap fee, in basis points of the fee, kept for the protocol instead of the
+    // reserves
+    protocol_fee_bps: u16,
+    // Accrued protocol fees per token, held by the AMM but not part of the reserves
+    protocol_fees: [Balance; 2],
+    // Also reject owner deposits until both token metadata have resolved
+    strict_init: bool,
+    // Consecutive failed output transfers after which swaps into that token are paused, e.g.
+    // for tokens that can freeze transfers. Opt-in, since a swapper that is not registered
+    // with the output token fails the transfer as well.
+    max_transfer_failures: Option<u32>,
+    shares: LookupMap<AccountId, Balance>,
+    shares_epoch: u32,
+    total_shares: Balance,
+    // Liquidity deposits of token A and token B waiting for the other side
+    pending_deposits: LookupMap<AccountId, (Balance, Balance)>,
+    // Sum of the pending deposits of token A and token B, held on top of the reserves
+    pending_totals: [Balance; 2],
+    // Ring buffer of the last emitted events as JSON, indexed by event number modulo its size
+    recent_events: LookupMap<u64, String>,
+    event_count: u64,
+    // Swapped input per account in whole tokens scaled by 10^18, both tokens summed
+    volume: LookupMap<AccountId, u128>,
+    // (minimum volume, discount in basis points of the fee), by ascending volume
+    fee_discount_tiers: Vec<(u128, u16)>,
+    // Result of the last probe_token per token, None until probed
+    token_compliance: [Option<bool>; 2],
+    // (balance_a, balance_b) before and after the last priced swap
+    last_swap_reserves: Option<(Balance, Balance, Balance, Balance)>,
+    // Static gas of the swap output transfers, some tokens need more than the default
+    transfer_gas: Gas,
+    // Uniswap V2 style sums of the raw spot prices (scaled by 10^18) times the seconds each
+    // held, for TWAP oracles. They wrap on overflow, so consumers only use differences.
+    price_0_cumulative: u128,
+    price_1_cumulative: u128,
+    // In seconds
+    last_update_timestamp: u64,
+    // Only these accounts can swap while set, e.g. during a launch window. None is public.
+    allowed_swappers: Option<UnorderedSet<AccountId>>,
+    // Soft cap in bytes. Above it, accounts without shares or a pending deposit cannot add
+    // liquidity, while existing providers can still deposit and withdraw.
+    max_storage_usage: Option<u64>,
 }
 
 #[near_bindgen]
 impl AMM {
     #[init]
-    pub fn new(owner: AccountId, token_a: AccountId, token_b: AccountId) -> Self {
-        let amm = Self {
+    pub fn new(
+        owner: AccountId,
+        token_a: AccountId,
+        token_b: AccountId,
+        blocked_tokens: Option<Vec<AccountId>>,
+    ) -> Self {
+        require!(token_a != token_b, "Tokens must be distinct.");
+        // Known-bad token addresses, e.g. a list a factory maintains and passes to every pool
+        let blocked_tokens = blocked_tokens.unwrap_or_default();
+        require!(
+            !blocked_tokens.contains(&token_a) && !blocked_tokens.contains(&token_b),
+            "Token is blocklisted."
+        );
+        // Metadata is not available yet, so indexers only get the addresses.
+        let pool_created = events::log_pool_created(&owner, &token_a, &token_b, DEFAULT_FEE_BPS);
+
+        let mut amm = Self {
             owner,
+            pending_owner: None,
             tokens: vec![Token::new(token_a.clone()), Token::new(token_b.clone())],
+            max_init_imbalance_bps: None,
+            has_swapped: false,
+            owner_can_swap: false,
+            max_drift_bps: None,
+            swaps_paused: false,
+            paused: false,
+            inactivity_pause_blocks: None,
+            last_owner_action_block: env::block_height(),
+            sandwich_detection: false,
+            recent_swaps: Vec::new(),
+            large_swap_threshold: None,
+            metadata_ttl_blocks: None,
+            min_execution_rate: None,
+            fee_bps: DEFAULT_FEE_BPS,
+            fee_history: Vec::new(),
+            protocol_fee_bps: 0,
+            protocol_fees: [0, 0],
+            strict_init: false,
+            max_transfer_failures: None,
+            shares: LookupMap::new(StorageKey::Shares { epoch: 0 }),
+            shares_epoch: 0,
+            total_shares: 0,
+            pending_deposits: LookupMap::new(StorageKey::PendingDeposits),
+            pending_totals: [0, 0],
+            recent_events: LookupMap::new(StorageKey::RecentEvents),
+            event_count: 0,
+            volume: LookupMap::new(StorageKey::Volume),
+            fee_discount_tiers: Vec::new(),
+            token_compliance: [None, None],
+            last_swap_reserves: None,
+            transfer_gas: TGAS,
+            price_0_cumulative: 0,
+            price_1_cumulative: 0,
+            last_update_timestamp: env::block_timestamp() / 1_000_000_000,
+            allowed_swappers: None,
+            max_storage_usage: None,
         };
+        amm.record_event(pool_created);
 
         amm.update_metadata(token_a);
         amm.update_metadata(token_b);
@@ -74,13 +655,8 @@ impl AMM {
         amm
     }
 
+    // Anyone can call it, e.g. to retry after a failed ft_metadata call
     pub fn update_metadata(&self, token: AccountId) -> Promise {
-        // Ensure the metadata update request is for the right token.
-        require!(
-            token == self.tokens[0].address || token == self.tokens[1].address,
-            "Wrong token provided."
-        );
-
         let index = self.get_token_index(token.clone());
         let promise = ft_metadata_ext(token.clone())
             .with_static_gas(TGAS)
@@ -93,17 +669,125 @@ impl AMM {
         )
     }
 
+    // Checks that the token answers the NEP-141 metadata and NEP-145 storage views
+    pub fn probe_token(&mut self, token: AccountId) -> Promise {
+        self.assert_owner();
+        let index = self.get_token_index(token.clone());
+        let metadata = ft_metadata_ext(token.clone())
+            .with_static_gas(TGAS)
+            .ft_metadata();
+        let storage_balance = ext_storage::ext(token)
+            .with_static_gas(TGAS)
+            .storage_balance_of(env::current_account_id());
+
+        metadata.and(storage_balance).then(
+            Self::ext(env::current_account_id())
+                .with_static_gas(TGAS)
+                .probe_token_callback(index),
+        )
+    }
+
+    #[private]
+    pub fn probe_token_callback(
+        &mut self,
+        index: usize,
+        #[callback_result] metadata: Result<FungibleTokenMetadata, PromiseError>,
+        #[callback_result] storage_balance: Result<Option<StorageBalance>, PromiseError>,
+    ) -> bool {
+        let compliant = metadata.is_ok() && storage_balance.is_ok();
+        self.token_compliance[index] = Some(compliant);
+        compliant
+    }
+
+    pub fn get_token_compliance(&self, token: AccountId) -> Option<bool> {
+        self.token_compliance[self.get_token_index(token)]
+    }
+
     #[private]
     pub fn metadata_callback(
         &mut self,
-        #[callback_unwrap] call_result: FungibleTokenMetadata,
+        #[callback_result] call_result: Result<FungibleTokenMetadata, PromiseError>,
         index: usize,
     ) {
-        self.tokens[index].metadata = Some(TokenMetadata::new(
-            call_result.name,
-            call_result.symbol,
-            call_result.decimals,
-        ));
+        self.tokens[index].metadata_refresh_pending = false;
+        // Keep the current metadata, or None before the first success, until a retry works
+        let metadata = match call_result {
+            Ok(metadata) => metadata,
+            Err(_) => {
+                log!("Retrieving the token metadata failed.");
+                return;
+            }
+        };
+
+        // The reserve math depends on the decimals, so a refresh keeps the ones already set
+        let decimals = match &self.tokens[index].metadata {
+            Some(current) => {
+                if current.decimals != metadata.decimals {
+                    log!("The token reported new decimals, keeping the current ones.");
+                }
+                current.decimals
+            }
+            None => metadata.decimals,
+        };
+        self.tokens[index].metadata =
+            Some(TokenMetadata::new(metadata.name, metadata.symbol, decimals));
+        self.tokens[index].last_metadata_block = env::block_height();
+    }
+
+    pub fn set_metadata_ttl_blocks(&mut self, metadata_ttl_blocks: Option<u64>) {
+        self.assert_owner();
+        self.metadata_ttl_blocks = metadata_ttl_blocks;
+    }
+
+    pub fn get_metadata_ttl_blocks(&self) -> Option<u64> {
+        self.metadata_ttl_blocks
+    }
+
+    pub fn get_metadata_status(&self, token: AccountId) -> MetadataStatus {
+        let index = self.get_token_index(token);
+        MetadataStatus {
+            initialized: self.tokens[index].metadata.is_some(),
+            last_update_block: self.tokens[index].last_metadata_block,
+            stale: self.is_metadata_stale(index),
+        }
+    }
+
+    // Owner deposits are credited before the metadata arrives, but the pool cannot be priced
+    // and swaps stay blocked until both tokens have metadata.
+    pub fn is_initialization_complete(&self) -> bool {
+        self.tokens.iter().all(|token| token.metadata.is_some())
+    }
+
+    // If ft_metadata fails for a token the pool cannot be priced. Retry with update_metadata.
+    pub fn set_strict_init(&mut self, strict_init: bool) {
+        self.assert_owner();
+        self.strict_init = strict_init;
+    }
+
+    pub fn get_strict_init(&self) -> bool {
+        self.strict_init
+    }
+
+    // Two steps, so a typo in the account cannot lock the owner out of the pool
+    pub fn propose_new_owner(&mut self, new_owner: AccountId) {
+        self.assert_owner();
+        self.pending_owner = Some(new_owner);
+    }
+
+    pub fn accept_ownership(&mut self) {
+        require!(
+            self.pending_owner == Some(env::predecessor_account_id()),
+            "Only the pending owner can accept ownership."
+        );
+        self.owner = self.pending_owner.take().unwrap();
+    }
+
+    pub fn get_owner(&self) -> AccountId {
+        self.owner.clone()
+    }
+
+    pub fn get_pending_owner(&self) -> Option<AccountId> {
+        self.pending_owner.clone()
     }
 
     pub fn get_metadata(&self, token: AccountId) -> TokenMetadata {
@@ -115,311 +799,5288 @@ impl AMM {
         self.tokens[index].metadata.clone().unwrap()
     }
 
+    // Cosmetic metadata corrections. Decimals can only change through update_metadata since
+    // the balance scaling depends on them.
+    pub fn set_token_name(&mut self, token: AccountId, name: String) {
+        self.assert_owner();
+        self.get_metadata_mut(token).name = name;
+    }
+
+    pub fn set_token_symbol(&mut self, token: AccountId, symbol: String) {
+        self.assert_owner();
+        self.get_metadata_mut(token).symbol = symbol;
+    }
+
+    pub fn try_get_metadata(&self, token: AccountId) -> Option<TokenMetadata> {
+        let index = self.get_token_index(token);
+        self.tokens[index].metadata.clone()
+    }
+
+    // Bulk lookup for routers. Tokens that are not in the pool are skipped.
+    pub fn get_metadatas(&self, tokens: Vec<AccountId>) -> Vec<Option<TokenMetadata>> {
+        require!(
+            tokens.len() <= MAX_METADATA_QUERIES,
+            "Too many tokens requested."
+        );
+
+        tokens
+            .into_iter()
+            .filter_map(|token| {
+                self.tokens
+                    .iter()
+                    .find(|pool_token| pool_token.address == token)
+                    .map(|pool_token| pool_token.metadata.clone())
+            })
+            .collect()
+    }
+
     pub fn get_balance(&self, token: AccountId) -> U128 {
         let index = self.get_token_index(token.clone());
         near_sdk::json_types::U128(self.tokens[index].balance)
     }
 
-    pub fn get_ratio(&self) -> U128 {
+    // Raw reserve ratios summed over time, scaled by 10^18:
+    // (token B per token A, token A per token B, last update in seconds)
+    pub fn get_cumulative_prices(&self) -> (U128, U128, u64) {
+        (
+            U128(self.price_0_cumulative),
+            U128(self.price_1_cumulative),
+            self.last_update_timestamp,
+        )
+    }
+
+    // (balance_a_before, balance_b_before, balance_a_after, balance_b_after) of the last swap
+    pub fn get_last_swap_reserves(&self) -> Option<(U128, U128, U128, U128)> {
+        self.last_swap_reserves
+            .map(|(a_before, b_before, a_after, b_after)| {
+                (U128(a_before), U128(b_before), U128(a_after), U128(b_after))
+            })
+    }
+
+    pub fn get_pool_info(&self) -> PoolInfo {
+        PoolInfo {
+            owner: self.owner.clone(),
+            token_a: self.tokens[0].address.clone(),
+            token_b: self.tokens[1].address.clone(),
+            metadata_a: self.tokens[0].metadata.clone(),
+            metadata_b: self.tokens[1].metadata.clone(),
+            reserve_a: U128(self.tokens[0].balance),
+            reserve_b: U128(self.tokens[1].balance),
+        }
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            reserve_a: U128(self.tokens[0].balance),
+            reserve_b: U128(self.tokens[1].balance),
+            ratio: self.get_ratio(),
+            price_a_in_b: self.get_spot_price(self.tokens[0].address.clone()),
+            price_b_in_a: self.get_spot_price(self.tokens[1].address.clone()),
+            fee_bps: self.fee_bps,
+            paused: self.paused,
+            swaps_paused: self.swaps_paused,
+            total_shares: U128(self.total_shares),
+        }
+    }
+
+    // Raw reserves of token A and token B, for clients that do their own decimal scaling
+    pub fn get_reserves(&self) -> (U128, U128) {
+        (U128(self.tokens[0].balance), U128(self.tokens[1].balance))
+    }
+
+    // Reserves with the decimal point inserted, e.g. "10.50000000" for 8 decimals
+    pub fn get_human_reserves(&self) -> (String, String) {
         require!(
-            self.tokens[0].metadata.is_some(),
-            "Metadata not initialized for index 0."
+            self.is_initialization_complete(),
+            "Pool metadata is not initialized."
         );
+
+        let format = |token: &Token| {
+            let decimals = token.metadata.as_ref().unwrap().decimals as usize;
+            let digits = format!("{:0>width$}", token.balance, width = decimals + 1);
+            let (whole, fraction) = digits.split_at(digits.len() - decimals);
+            if fraction.is_empty() {
+                whole.to_string()
+            } else {
+                format!("{}.{}", whole, fraction)
+            }
+        };
+        (format(&self.tokens[0]), format(&self.tokens[1]))
+    }
+
+    pub fn export_state(&self) -> PoolState {
+        PoolState {
+            owner: self.owner.clone(),
+            pending_owner: self.pending_owner.clone(),
+            tokens: self
+                .tokens
+                .iter()
+                .map(|token| TokenState {
+                    address: token.address.clone(),
+                    reserve: U128(token.balance),
+                    metadata: token.metadata.clone(),
+                    max_single_deposit: token.max_single_deposit.map(U128),
+                    output_paused: token.output_paused,
+                })
+                .collect(),
+            fee_bps: self.fee_bps,
+            protocol_fee_bps: self.protocol_fee_bps,
+            swaps_paused: self.swaps_paused,
+            paused: self.paused,
+            has_swapped: self.has_swapped,
+            owner_can_swap: self.owner_can_swap,
+            max_init_imbalance_bps: self.max_init_imbalance_bps.map(U128),
+            max_drift_bps: self.max_drift_bps,
+            min_execution_rate: self.min_execution_rate.map(U128),
+            large_swap_threshold: self.large_swap_threshold.map(U128),
+            metadata_ttl_blocks: self.metadata_ttl_blocks,
+            sandwich_detection: self.sandwich_detection,
+            strict_init: self.strict_init,
+            max_transfer_failures: self.max_transfer_failures,
+            total_shares: U128(self.total_shares),
+        }
+    }
+
+    pub fn get_shares(&self, account_id: AccountId) -> U128 {
+        U128(self.shares.get(&account_id).unwrap_or(0))
+    }
+
+    pub fn get_minimum_liquidity(&self) -> U128 {
+        U128(MINIMUM_LIQUIDITY)
+    }
+
+    pub fn get_total_shares(&self) -> U128 {
+        U128(self.total_shares)
+    }
+
+    pub fn get_pending_deposit(&self, account_id: AccountId) -> (U128, U128) {
+        let (amount_a, amount_b) = self.pending_deposits.get(&account_id).unwrap_or((0, 0));
+        (U128(amount_a), U128(amount_b))
+    }
+
+    // The off-ratio excess of a liquidity deposit is refunded, so only the matched amounts join
+    // the reserves
+    pub fn simulate_reserves_after_add(&self, amount_a: U128, amount_b: U128) -> (U128, U128) {
+        require!(amount_a.0 > 0 && amount_b.0 > 0, "Amount must be positive.");
+        self.assert_funded_on_both_sides();
+        let (used_a, used_b) = self.match_pool_ratio(amount_a.0, amount_b.0);
+        (
+            U128(self.tokens[0].balance + used_a),
+            U128(self.tokens[1].balance + used_b),
+        )
+    }
+
+    // Sends the caller's pending deposit back, e.g. one side of a deposit whose other side
+    // never arrived. The deposit is cleared up front and restored if a transfer fails.
+    pub fn withdraw_pending(&mut self) -> Promise {
+        let account_id = env::predecessor_account_id();
+        let (amount_a, amount_b) = self.pending_deposits.get(&account_id).unwrap_or((0, 0));
         require!(
-            self.tokens[1].metadata.is_some(),
-            "Metadata not initialized for index 1."
+            amount_a > 0 || amount_b > 0,
+            "No pending deposit to withdraw."
         );
+        self.set_pending_deposit(&account_id, (0, 0));
+        self.invalidate_balance_cache();
 
-        let balance_a: u128 = self.tokens[0].balance
-            / 10_u128.pow(self.tokens[0].metadata.clone().unwrap().decimals.into());
-        let balance_b: u128 = self.tokens[1].balance
-            / 10_u128.pow(self.tokens[1].metadata.clone().unwrap().decimals.into());
+        let mut withdrawals: Option<Promise> = None;
+        for (index, amount) in [amount_a, amount_b].into_iter().enumerate() {
+            if amount == 0 {
+                continue;
+            }
+            let withdrawal = ft_core_ext(self.tokens[index].address.clone())
+                .with_static_gas(TGAS)
+                .with_attached_deposit(ONE_YOCTO)
+                .ft_transfer(account_id.clone(), amount.into(), None)
+                .then(
+                    Self::ext(env::current_account_id())
+                        .with_static_gas(TGAS)
+                        .withdraw_pending_callback(account_id.clone(), index, amount),
+                );
+            withdrawals = Some(match withdrawals {
+                Some(previous) => previous.and(withdrawal),
+                None => withdrawal,
+            });
+        }
 
-        near_sdk::json_types::U128(balance_a * balance_b)
+        withdrawals.unwrap()
     }
 
     #[private]
-    pub fn swap_callback(
+    pub fn withdraw_pending_callback(
         &mut self,
-        balance_a: Balance,
-        balance_b: Balance,
+        account_id: AccountId,
+        index: usize,
         amount: Balance,
         #[callback_result] call_result: Result<(), PromiseError>,
-    ) -> PromiseOrValue<U128> {
+    ) {
         if call_result.is_err() {
-            // Return the deposited tokens if the swap fails
-            log!("Transfering the swapped tokens failed.");
-            PromiseOrValue::Value(amount.into())
-        } else {
-            // Update the AMM balances
-            self.tokens[0].balance = balance_a;
-            self.tokens[1].balance = balance_b;
-
-            PromiseOrValue::Value(0.into())
+            log!("Transfering the pending deposit failed.");
+            self.credit_pending_deposit(&account_id, index, amount);
         }
     }
-}
 
-impl AMM {
-    fn get_token_index(&self, token: AccountId) -> usize{
-        if token == self.tokens[0].address {
-            0
+    // Burns the shares and sends the caller their part of both reserves. The reserves are
+    // reduced up front so swaps in the meantime cannot use the withdrawn tokens.
+    pub fn remove_liquidity(&mut self, shares: U128) -> Promise {
+        let account_id = env::predecessor_account_id();
+        let account_shares = self.shares.get(&account_id).unwrap_or(0);
+        require!(shares.0 > 0, "Amount must be positive.");
+        require!(shares.0 <= account_shares, "Not enough shares.");
+
+        let total_shares = U256::from(self.total_shares);
+        let amount_a =
+            (U256::from(shares.0) * U256::from(self.tokens[0].balance) / total_shares).as_u128();
+        let amount_b =
+            (U256::from(shares.0) * U256::from(self.tokens[1].balance) / total_shares).as_u128();
+        // Tokens reject zero-amount transfers, which would fail one side of the withdrawal
+        require!(
+            amount_a > 0 && amount_b > 0,
+            "Shares are worth 0 of one of the tokens."
+        );
+
+        // Providers leaving the pool free their entry, which prunes storage below the cap
+        if account_shares == shares.0 {
+            self.shares.remove(&account_id);
         } else {
-            1
+            self.shares
+                .insert(&account_id, &(account_shares - shares.0));
         }
+        self.total_shares -= shares.0;
+        self.update_oracle();
+        self.tokens[0].balance -= amount_a;
+        self.tokens[1].balance -= amount_b;
+        self.invalidate_balance_cache();
+
+        ft_core_ext(self.tokens[0].address.clone())
+            .with_static_gas(TGAS)
+            .with_attached_deposit(ONE_YOCTO)
+            .ft_transfer(account_id.clone(), amount_a.into(), None)
+            .and(
+                ft_core_ext(self.tokens[1].address.clone())
+                    .with_static_gas(TGAS)
+                    .with_attached_deposit(ONE_YOCTO)
+                    .ft_transfer(account_id.clone(), amount_b.into(), None),
+            )
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(TGAS)
+                    .remove_liquidity_callback(account_id, shares, amount_a, amount_b),
+            )
     }
 
-    fn owner_deposit(&mut self, token_in: usize, amount: Balance) {
-        self.tokens[token_in].balance += amount;
+    pub fn get_transfer_deposit(&self) -> U128 {
+        U128(ONE_YOCTO)
     }
 
-    fn swap(&mut self, sender_id: AccountId, token_in: usize, amount: Balance) -> Promise {
-        let token_out = 1 - token_in;
+    // Bytes of contract storage, which determine the NEAR locked for storage staking
+    pub fn get_storage_usage(&self) -> u64 {
+        env::storage_usage()
+    }
+
+    pub fn set_max_storage_usage(&mut self, max_storage_usage: Option<u64>) {
+        self.assert_owner();
+        self.max_storage_usage = max_storage_usage;
+    }
 
-        let new_balance_a = self.tokens[token_in].balance + amount;
+    pub fn get_max_storage_usage(&self) -> Option<u64> {
+        self.max_storage_usage
+    }
 
-        // Avoid multiplication overflow by using U256
-        let token_out_amount = ((U256::from(self.tokens[token_out].balance) * U256::from(amount))
-            / new_balance_a)
-            .as_u128();
+    // NEAR balance not locked for storage staking
+    pub fn get_available_near(&self) -> U128 {
+        let storage_cost = Balance::from(env::storage_usage()) * env::storage_byte_cost();
+        U128(env::account_balance().saturating_sub(storage_cost))
+    }
+
+    // Warnings for operators, empty while the pool is healthy
+    pub fn health_check(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+        if self.get_available_near().0 < MIN_AVAILABLE_NEAR {
+            warnings
+                .push("Available NEAR balance is too low to attach transfer deposits.".to_string());
+        }
+        if self.swaps_paused || self.paused {
+            warnings.push("Swaps are paused.".to_string());
+        }
+        warnings
+    }
+
+    // Lets routers pick the right quoting formula. Only the x * y = k pool exists for now.
+    pub fn get_invariant_type(&self) -> String {
+        "constant_product".to_string()
+    }
 
+    // Product of the reserves in whole tokens, truncated to an integer. Use `get_reserves`
+    // for the exact amounts.
+    pub fn get_ratio(&self) -> U128 {
         require!(
-            token_out_amount <= self.tokens[token_out].balance,
-            "Not enough funds to complete the trade."
+            self.tokens[0].metadata.is_some(),
+            "Metadata not initialized for index 0."
         );
-        require!(token_out_amount > 0, "Cannot swap for 0 tokens.");
+        require!(
+            self.tokens[1].metadata.is_some(),
+            "Metadata not initialized for index 1."
+        );
+
+        let balance_a = self.tokens[0].balance;
+        let balance_b = self.tokens[1].balance;
+        let decimals = u32::from(self.tokens[0].metadata.as_ref().unwrap().decimals)
+            + u32::from(self.tokens[1].metadata.as_ref().unwrap().decimals);
+
+        // Scale the product once so that sub-unit balances are not truncated to 0 separately
+        let ratio = (U256::from(balance_a) * U256::from(balance_b)
+            / U256::exp10(decimals as usize))
+        .as_u128();
+
+        // A pool funded on both sides never reports a ratio of 0
+        if ratio == 0 && balance_a > 0 && balance_b > 0 {
+            return near_sdk::json_types::U128(1);
+        }
+
+        near_sdk::json_types::U128(ratio)
+    }
 
-        let new_balance_b = self.tokens[token_out].balance - token_out_amount;
+    // Credit tokens the owner sent with plain `ft_transfer` calls. The amounts are only accepted
+    // if the AMM actually holds them on top of the tracked reserves.
+    pub fn record_seed(&mut self, amount_a: U128, amount_b: U128) -> Promise {
+        self.assert_owner();
+        // A seed without shares would be a donation to the liquidity providers
+        require!(
+            self.total_shares == 0,
+            "Add liquidity instead once the pool has liquidity providers."
+        );
+        require!(amount_a.0 > 0 || amount_b.0 > 0, "Amount must be positive.");
 
-        ft_core_ext(self.tokens[token_out].address.clone())
+        ft_core_ext(self.tokens[0].address.clone())
             .with_static_gas(TGAS)
-            .with_attached_deposit(1)
-            .ft_transfer(sender_id, token_out_amount.into(), None)
+            .ft_balance_of(env::current_account_id())
+            .and(
+                ft_core_ext(self.tokens[1].address.clone())
+                    .with_static_gas(TGAS)
+                    .ft_balance_of(env::current_account_id()),
+            )
             .then(
                 Self::ext(env::current_account_id())
                     .with_static_gas(TGAS)
-                    .swap_callback(new_balance_a, new_balance_b, amount),
+                    .record_seed_callback(amount_a, amount_b),
             )
     }
-}
 
-#[near_bindgen]
-impl FungibleTokenReceiver for AMM {
-    fn ft_on_transfer(
-        &mut self,
-        sender_id: AccountId,
-        amount: U128,
-        msg: String,
-    ) -> PromiseOrValue<U128> {
-        drop(msg);
-        let predecessor_id = env::predecessor_account_id();
+    // Records a seed that matches the current ratio, so the spot price does not move
+    pub fn add_proportional(&mut self, amount_a: U128, amount_b: U128) -> Promise {
+        self.assert_owner();
+        let balance_a = self.tokens[0].balance;
+        let balance_b = self.tokens[1].balance;
         require!(
-            predecessor_id == self.tokens[0].address || predecessor_id == self.tokens[1].address,
-            "Token not supported."
+            balance_a > 0 && balance_b > 0,
+            "Cannot add proportionally to an empty pool."
         );
+        require!(amount_a.0 > 0 && amount_b.0 > 0, "Amount must be positive.");
 
-        let amount: Balance = amount.into();
-        require!(amount > 0, "Amount must be positive.");
-
-        let token_in: usize = self.get_token_index(predecessor_id);
-
-        if sender_id == self.owner {
-            self.owner_deposit(token_in, amount);
-            PromiseOrValue::Value(near_sdk::json_types::U128(0))
+        // amount_a / amount_b compared with balance_a / balance_b
+        let added_ratio = U256::from(amount_a.0) * U256::from(balance_b);
+        let pool_ratio = U256::from(amount_b.0) * U256::from(balance_a);
+        let deviation = if added_ratio > pool_ratio {
+            added_ratio - pool_ratio
         } else {
-            self.swap(sender_id, token_in, amount).into()
+            pool_ratio - added_ratio
+        };
+        require!(
+            deviation * U256::from(BPS_DENOMINATOR)
+                <= pool_ratio * U256::from(PROPORTIONAL_TOLERANCE_BPS),
+            "Amounts do not match the pool ratio."
+        );
+
+        self.record_seed(amount_a, amount_b)
+    }
+
+    #[private]
+    pub fn record_seed_callback(
+        &mut self,
+        amount_a: U128,
+        amount_b: U128,
+        #[callback_result] balance_a: Result<U128, PromiseError>,
+        #[callback_result] balance_b: Result<U128, PromiseError>,
+    ) {
+        require!(
+            balance_a.is_ok() && balance_b.is_ok(),
+            "Failed to retrieve the AMM token balances."
+        );
+
+        let new_balance_a = self.tokens[0].balance + amount_a.0;
+        let new_balance_b = self.tokens[1].balance + amount_b.0;
+        // Protocol fees and pending liquidity deposits are held on top of the reserves
+        require!(
+            new_balance_a + self.protocol_fees[0] + self.pending_totals[0] <= balance_a.unwrap().0
+                && new_balance_b + self.protocol_fees[1] + self.pending_totals[1]
+                    <= balance_b.unwrap().0,
+            "Seed amounts exceed the untracked token balances."
+        );
+
+        self.update_oracle();
+        self.tokens[0].balance = new_balance_a;
+        self.tokens[1].balance = new_balance_b;
+        self.invalidate_balance_cache();
+
+        if !self.has_swapped {
+            self.assert_init_balanced();
         }
     }
-}
 
-/*
+    pub fn set_max_init_imbalance_bps(&mut self, max_init_imbalance_bps: Option<U128>) {
+        self.assert_owner();
+        if let Some(max_imbalance) = max_init_imbalance_bps {
+            require!(
+                max_imbalance.0 >= BPS_DENOMINATOR,
+                "Imbalance cannot be lower than 10000 bps (1:1)."
+            );
+        }
+        self.max_init_imbalance_bps = max_init_imbalance_bps.map(|max_imbalance| max_imbalance.0);
+    }
 
-near call dev-1672856405734-99444394530569 new '{"owner": "ammdev.testnet", "token_a": "ft.predeployed.examples.testnet", "token_b": "ft.predeployed.examples.testnet"}' --account-id ammdev.testnet --gas=300000000000000
+    pub fn get_max_init_imbalance_bps(&self) -> Option<U128> {
+        self.max_init_imbalance_bps.map(U128)
+    }
 
-near call ft.predeployed.examples.testnet storage_deposit '{"account_id": "dev-1672856405734-99444394530569"}' --accountId ammdev.testnet --amount 0.00125
+    // Round-trip cost of swapping 0.01% of the token A reserve to token B and back
+    pub fn get_spread_bps(&self) -> u16 {
+        let balance_a = self.tokens[0].balance;
+        let balance_b = self.tokens[1].balance;
+        require!(
+            balance_a > 0 && balance_b > 0,
+            "Cannot compute the spread for an empty pool."
+        );
 
-near call dev-1672856405734-99444394530569 get_metadata '{"ind": 0}' --account-id ammdev.testnet
-near call dev-1672856405734-99444394530569 get_balance '{"ind": 0}' --account-id ammdev.testnet
+        let reference_amount = (balance_a / BPS_DENOMINATOR).max(1);
+        let amount_b = self.quote_amount_out(balance_a, balance_b, reference_amount);
+        let amount_a =
+            self.quote_amount_out(balance_b - amount_b, balance_a + reference_amount, amount_b);
+
+        ((reference_amount - amount_a) * BPS_DENOMINATOR / reference_amount) as u16
+    }
+
+    pub fn set_max_drift_bps(&mut self, max_drift_bps: Option<u16>) {
+        self.assert_owner();
+        if let Some(max_drift) = max_drift_bps {
+            require!(
+                u128::from(max_drift) < BPS_DENOMINATOR,
+                "Drift must be lower than 10000 bps."
+            );
+        }
+        self.max_drift_bps = max_drift_bps;
+    }
+
+    pub fn get_max_drift_bps(&self) -> Option<u16> {
+        self.max_drift_bps
+    }
+
+    pub fn get_swaps_paused(&self) -> bool {
+        self.swaps_paused
+    }
+
+    // Compares the tracked reserves against the actual token balances. Anyone can call it,
+    // since it can only pause swaps.
+    pub fn reconcile(&mut self) -> PromiseOrValue<bool> {
+        if let (Some(balance_a), Some(balance_b)) =
+            (self.get_fresh_balance(0), self.get_fresh_balance(1))
+        {
+            return PromiseOrValue::Value(self.check_drift(balance_a, balance_b));
+        }
+
+        ft_core_ext(self.tokens[0].address.clone())
+            .with_static_gas(TGAS)
+            .ft_balance_of(env::current_account_id())
+            .and(
+                ft_core_ext(self.tokens[1].address.clone())
+                    .with_static_gas(TGAS)
+                    .ft_balance_of(env::current_account_id()),
+            )
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(TGAS)
+                    .reconcile_callback(),
+            )
+            .into()
+    }
+
+    // Returns whether the balances are within the tolerance. Untracked surplus (e.g. plain
+    // transfers waiting for `record_seed`) is not drift, only a shortfall is.
+    #[private]
+    pub fn reconcile_callback(
+        &mut self,
+        #[callback_result] balance_a: Result<U128, PromiseError>,
+        #[callback_result] balance_b: Result<U128, PromiseError>,
+    ) -> bool {
+        require!(
+            balance_a.is_ok() && balance_b.is_ok(),
+            "Failed to retrieve the AMM token balances."
+        );
+
+        let (balance_a, balance_b) = (balance_a.unwrap().0, balance_b.unwrap().0);
+        self.cache_balances(balance_a, balance_b);
+        self.check_drift(balance_a, balance_b)
+    }
+
+    pub fn get_cached_balance(&self, token: AccountId) -> Option<(U128, u64)> {
+        let index = self.get_token_index(token);
+        self.tokens[index]
+            .cached_balance
+            .map(|(balance, timestamp)| (U128(balance), timestamp))
+    }
+
+    // The owner unpauses once the balances are fixed, e.g. by depositing the missing tokens
+    pub fn resume_swaps(&mut self) {
+        self.assert_owner();
+        self.swaps_paused = false;
+    }
+
+    pub fn set_paused(&mut self, paused: bool) {
+        self.assert_owner();
+        self.paused = paused;
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn set_inactivity_pause_blocks(&mut self, inactivity_pause_blocks: Option<u64>) {
+        self.assert_owner();
+        require!(
+            inactivity_pause_blocks != Some(0),
+            "Inactivity window must be positive."
+        );
+        self.inactivity_pause_blocks = inactivity_pause_blocks;
+    }
+
+    pub fn get_inactivity_pause_blocks(&self) -> Option<u64> {
+        self.inactivity_pause_blocks
+    }
+
+    // None while the inactivity pause is disabled, 0 once the window has passed
+    pub fn get_blocks_until_inactivity_pause(&self) -> Option<u64> {
+        self.inactivity_pause_blocks.map(|window| {
+            (self.last_owner_action_block + window).saturating_sub(env::block_height())
+        })
+    }
+
+    pub fn set_fee_bps(&mut self, fee_bps: u16) {
+        self.assert_owner();
+        require!(fee_bps <= MAX_FEE_BPS, "Fee cannot exceed 1000 bps.");
+        let timestamp = env::block_timestamp();
+        self.record_event(events::log_fee_changed(self.fee_bps, fee_bps, timestamp));
+
+        if self.fee_history.len() == FEE_HISTORY_SIZE {
+            self.fee_history.remove(0);
+        }
+        self.fee_history.push((timestamp, fee_bps));
+        self.fee_bps = fee_bps;
+    }
+
+    pub fn get_fee_bps(&self) -> u16 {
+        self.fee_bps
+    }
+
+    pub fn get_fee_history(&self) -> Vec<(u64, u16)> {
+        self.fee_history.clone()
+    }
+
+    // Accounts whose swapped volume reaches a tier pay the fee minus that tier's discount
+    pub fn set_fee_discount_tiers(&mut self, tiers: Vec<(U128, u16)>) {
+        self.assert_owner();
+        require!(
+            tiers.len() <= MAX_FEE_DISCOUNT_TIERS,
+            "Too many fee discount tiers."
+        );
+        require!(
+            tiers.windows(2).all(|pair| pair[0].0 .0 < pair[1].0 .0),
+            "Fee discount tiers must have ascending volumes."
+        );
+        require!(
+            tiers
+                .iter()
+                .all(|(_, discount_bps)| u128::from(*discount_bps) <= BPS_DENOMINATOR),
+            "Fee discount cannot exceed 10000 bps."
+        );
+        self.fee_discount_tiers = tiers
+            .into_iter()
+            .map(|(volume, discount_bps)| (volume.0, discount_bps))
+            .collect();
+    }
+
+    pub fn get_fee_discount_tiers(&self) -> Vec<(U128, u16)> {
+        self.fee_discount_tiers
+            .iter()
+            .map(|(volume, discount_bps)| (U128(*volume), *discount_bps))
+            .collect()
+    }
+
+    // Total input of the account's committed swaps. Token decimals differ, so each input is
+    // counted in whole tokens scaled by 10^18: 1 TA and 1 TB both add 10^18.
+    pub fn get_volume(&self, account_id: AccountId) -> U128 {
+        U128(self.volume.get(&account_id).unwrap_or(0))
+    }
+
+    // Swap fee paid by the account after its volume discount
+    pub fn get_account_fee_bps(&self, account_id: AccountId) -> u16 {
+        let volume = self.volume.get(&account_id).unwrap_or(0);
+        let discount_bps = self
+            .fee_discount_tiers
+            .iter()
+            .rev()
+            .find(|(min_volume, _)| volume >= *min_volume)
+            .map_or(0, |(_, discount_bps)| *discount_bps);
+
+        (u128::from(self.fee_bps) * (BPS_DENOMINATOR - u128::from(discount_bps)) / BPS_DENOMINATOR)
+            as u16
+    }
+
+    pub fn set_protocol_fee_bps(&mut self, protocol_fee_bps: u16) {
+        self.assert_owner();
+        require!(
+            u128::from(protocol_fee_bps) <= BPS_DENOMINATOR,
+            "Protocol fee cannot exceed 10000 bps."
+        );
+        self.protocol_fee_bps = protocol_fee_bps;
+    }
+
+    pub fn get_protocol_fee_bps(&self) -> u16 {
+        self.protocol_fee_bps
+    }
+
+    pub fn get_protocol_fees(&self, token: AccountId) -> U128 {
+        U128(self.protocol_fees[self.get_token_index(token)])
+    }
+
+    // Sends the accrued protocol fees of the token to the owner
+    pub fn withdraw_protocol_fees(&mut self, token: AccountId) -> Promise {
+        self.assert_owner();
+        let index = self.get_token_index(token.clone());
+        let amount = self.protocol_fees[index];
+        require!(amount > 0, "No protocol fees to withdraw.");
+        self.protocol_fees[index] = 0;
+        self.invalidate_balance_cache();
+
+        ft_core_ext(token)
+            .with_static_gas(TGAS)
+            .with_attached_deposit(ONE_YOCTO)
+            .ft_transfer(self.owner.clone(), amount.into(), None)
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(TGAS)
+                    .withdraw_protocol_fees_callback(index, amount),
+            )
+    }
+
+    #[private]
+    pub fn withdraw_protocol_fees_callback(
+        &mut self,
+        index: usize,
+        amount: Balance,
+        #[callback_result] call_result: Result<(), PromiseError>,
+    ) {
+        if call_result.is_err() {
+            log!("Transfering the protocol fees failed.");
+            self.protocol_fees[index] += amount;
+        }
+    }
+
+    // Rescue path for a paused pool: sends both reserves and the protocol fees to `receiver`.
+    // Like the other withdrawals they are cleared up front and restored if a transfer fails.
+    // Pending deposits are not pool funds and stay withdrawable by their accounts.
+    pub fn emergency_withdraw(&mut self, receiver: AccountId) -> Promise {
+        self.assert_owner();
+        require!(self.paused, "The pool must be paused.");
+        let reserves = [self.tokens[0].balance, self.tokens[1].balance];
+        let fees = self.protocol_fees;
+        require!(
+            reserves.iter().chain(fees.iter()).any(|amount| *amount > 0),
+            "No funds to withdraw."
+        );
+        self.update_oracle();
+
+        let mut withdrawals: Option<Promise> = None;
+        for index in 0..2 {
+            let amount = reserves[index] + fees[index];
+            if amount == 0 {
+                continue;
+            }
+            self.tokens[index].balance = 0;
+            self.protocol_fees[index] = 0;
+
+            let withdrawal = ft_core_ext(self.tokens[index].address.clone())
+                .with_static_gas(TGAS)
+                .with_attached_deposit(ONE_YOCTO)
+                .ft_transfer(receiver.clone(), amount.into(), None);
+            withdrawals = Some(match withdrawals {
+                Some(previous) => previous.and(withdrawal),
+                None => withdrawal,
+            });
+        }
+        self.invalidate_balance_cache();
+
+        withdrawals.unwrap().then(
+            Self::ext(env::current_account_id())
+                .with_static_gas(TGAS)
+                .emergency_withdraw_callback(reserves, fees),
+        )
+    }
+
+    // The promise results are in token order, skipping the tokens that had nothing to send.
+    // Once everything was sent the shares no longer back any funds, so they are cleared.
+    #[private]
+    pub fn emergency_withdraw_callback(&mut self, reserves: [Balance; 2], fees: [Balance; 2]) {
+        let mut result_index = 0;
+        let mut all_sent = true;
+        for index in 0..2 {
+            if reserves[index] + fees[index] == 0 {
+                continue;
+            }
+            let sent = matches!(
+                env::promise_result(result_index),
+                PromiseResult::Successful(_)
+            );
+            result_index += 1;
+            if !sent {
+                log!("Transfering the withdrawn reserve failed.");
+                all_sent = false;
+                self.update_oracle();
+                self.tokens[index].balance += reserves[index];
+                self.protocol_fees[index] += fees[index];
+            }
+        }
+
+        if all_sent {
+            // A LookupMap cannot be cleared, so the old entries are left behind a new prefix
+            self.shares_epoch += 1;
+            self.shares = LookupMap::new(StorageKey::Shares {
+                epoch: self.shares_epoch,
+            });
+            self.total_shares = 0;
+            log!("The pool was emptied, all shares were cleared.");
+        }
+    }
+
+    // Up to `limit` of the latest events, oldest first, for clients without a log indexer
+    pub fn get_recent_events(&self, limit: u64) -> Vec<near_sdk::serde_json::Value> {
+        let count = limit.min(RECENT_EVENTS_SIZE).min(self.event_count);
+        (self.event_count - count..self.event_count)
+            .map(|index| {
+                let event = self
+                    .recent_events
+                    .get(&(index % RECENT_EVENTS_SIZE))
+                    .unwrap();
+                near_sdk::serde_json::from_str(&event).unwrap()
+            })
+            .collect()
+    }
+
+    pub fn set_min_execution_rate(&mut self, min_execution_rate: Option<U128>) {
+        self.assert_owner();
+        self.min_execution_rate = min_execution_rate.map(|rate| rate.0);
+    }
+
+    pub fn get_min_execution_rate(&self) -> Option<U128> {
+        self.min_execution_rate.map(U128)
+    }
+
+    pub fn set_large_swap_threshold(&mut self, large_swap_threshold: Option<U128>) {
+        self.assert_owner();
+        self.large_swap_threshold = large_swap_threshold.map(|threshold| threshold.0);
+    }
+
+    pub fn get_large_swap_threshold(&self) -> Option<U128> {
+        self.large_swap_threshold.map(U128)
+    }
+
+    pub fn set_max_transfer_failures(&mut self, max_transfer_failures: Option<u32>) {
+        self.assert_owner();
+        require!(
+            max_transfer_failures != Some(0),
+            "Transfer failure limit must be positive."
+        );
+        self.max_transfer_failures = max_transfer_failures;
+    }
+
+    pub fn get_max_transfer_failures(&self) -> Option<u32> {
+        self.max_transfer_failures
+    }
+
+    // Turns a public pool private with the account as its only swapper
+    pub fn add_allowed_swapper(&mut self, account_id: AccountId) {
+        self.assert_owner();
+        self.allowed_swappers
+            .get_or_insert_with(|| UnorderedSet::new(StorageKey::AllowedSwappers))
+            .insert(&account_id);
+    }
+
+    pub fn remove_allowed_swapper(&mut self, account_id: AccountId) {
+        self.assert_owner();
+        require!(self.allowed_swappers.is_some(), "The pool is public.");
+        self.allowed_swappers.as_mut().unwrap().remove(&account_id);
+    }
+
+    // Opens the pool to every account again
+    pub fn clear_allowed_swappers(&mut self) {
+        self.assert_owner();
+        if let Some(mut allowed_swappers) = self.allowed_swappers.take() {
+            allowed_swappers.clear();
+        }
+    }
+
+    pub fn is_allowed_swapper(&self, account_id: AccountId) -> bool {
+        self.allowed_swappers
+            .as_ref()
+            .is_none_or(|allowed_swappers| allowed_swappers.contains(&account_id))
+    }
+
+    pub fn set_transfer_gas(&mut self, tgas: u64) {
+        self.assert_owner();
+        // 300 TGas is the most a transaction can attach
+        require!(
+            tgas > 0 && tgas <= 300,
+            "Transfer gas must be between 1 and 300 TGas."
+        );
+        self.transfer_gas = Gas::ONE_TERA * tgas;
+    }
+
+    // In TGas
+    pub fn get_transfer_gas(&self) -> u64 {
+        self.transfer_gas.0 / Gas::ONE_TERA.0
+    }
+
+    pub fn get_transfer_failures(&self, token: AccountId) -> u32 {
+        self.tokens[self.get_token_index(token)].transfer_failures
+    }
+
+    pub fn is_output_paused(&self, token: AccountId) -> bool {
+        self.tokens[self.get_token_index(token)].output_paused
+    }
+
+    pub fn resume_output(&mut self, token: AccountId) {
+        self.assert_owner();
+        let index = self.get_token_index(token);
+        self.tokens[index].output_paused = false;
+        self.tokens[index].transfer_failures = 0;
+    }
+
+    pub fn set_sandwich_detection(&mut self, sandwich_detection: bool) {
+        self.assert_owner();
+        self.sandwich_detection = sandwich_detection;
+        if !sandwich_detection {
+            self.recent_swaps.clear();
+        }
+    }
+
+    pub fn get_sandwich_detection(&self) -> bool {
+        self.sandwich_detection
+    }
+
+    pub fn set_owner_can_swap(&mut self, owner_can_swap: bool) {
+        self.assert_owner();
+        self.owner_can_swap = owner_can_swap;
+    }
+
+    pub fn get_owner_can_swap(&self) -> bool {
+        self.owner_can_swap
+    }
+
+    pub fn set_max_single_deposit(&mut self, token: AccountId, max_single_deposit: Option<U128>) {
+        self.assert_owner();
+        let index = self.get_token_index(token);
+        self.tokens[index].max_single_deposit = max_single_deposit.map(|max_deposit| max_deposit.0);
+    }
+
+    pub fn get_max_single_deposit(&self, token: AccountId) -> Option<U128> {
+        let index = self.get_token_index(token);
+        self.tokens[index].max_single_deposit.map(U128)
+    }
+
+    // The output `swap` would pay for `amount_in`, fee included. With `account_id` the fee is
+    // the one that account pays after its volume discount, otherwise the base fee.
+    pub fn get_quote(
+        &self,
+        token_in: AccountId,
+        amount_in: U128,
+        account_id: Option<AccountId>,
+    ) -> U128 {
+        let token_in = self.get_token_index(token_in);
+        let balance_in = self.tokens[token_in].balance;
+        let balance_out = self.tokens[1 - token_in].balance;
+        if balance_in == 0 || balance_out == 0 {
+            return U128(0);
+        }
+
+        U128(Self::quote_amount_out_at_fee(
+            balance_in,
+            balance_out,
+            amount_in.0,
+            self.get_quote_fee_bps(account_id),
+        ))
+    }
+
+    // Smallest input, fee included, for which `swap` pays at least `amount_out`. The fee is
+    // chosen as in `get_quote`.
+    pub fn get_amount_in(
+        &self,
+        token_out: AccountId,
+        amount_out: U128,
+        account_id: Option<AccountId>,
+    ) -> U128 {
+        let token_out = self.get_token_index(token_out);
+        let balance_in = self.tokens[1 - token_out].balance;
+        let balance_out = self.tokens[token_out].balance;
+        require!(
+            balance_in > 0 && balance_out > 0,
+            "Cannot quote an empty pool."
+        );
+        require!(
+            amount_out.0 < balance_out,
+            "Not enough funds to complete the trade."
+        );
+
+        // Both divisions round up, so the quote for the result never falls short
+        let amount_in_after_fee = (U256::from(balance_in) * U256::from(amount_out.0)
+            + (balance_out - amount_out.0 - 1))
+            / (balance_out - amount_out.0);
+        let fee_bps = self.get_quote_fee_bps(account_id);
+        let fee_denominator = U256::from(BPS_DENOMINATOR - u128::from(fee_bps));
+        let amount_in = (amount_in_after_fee * U256::from(BPS_DENOMINATOR) + fee_denominator
+            - U256::one())
+            / fee_denominator;
+
+        U128(amount_in.as_u128())
+    }
+
+    // Token and amount to swap so the holdings end up with equal value at the spot price.
+    // Fees and price impact are not included.
+    pub fn compute_rebalance_swap(&self, holding_a: U128, holding_b: U128) -> (AccountId, U128) {
+        let (balance_a, balance_b) = (self.tokens[0].balance, self.tokens[1].balance);
+        require!(
+            balance_a > 0 && balance_b > 0,
+            "Cannot compute the rebalance for an empty pool."
+        );
+
+        // Both holdings valued in token B units times balance_a
+        let value_a = U256::from(holding_a.0) * U256::from(balance_b);
+        let value_b = U256::from(holding_b.0) * U256::from(balance_a);
+        if value_a >= value_b {
+            let amount = (value_a - value_b) / (U256::from(balance_b) * 2);
+            (self.tokens[0].address.clone(), U128(amount.as_u128()))
+        } else {
+            let amount = (value_b - value_a) / (U256::from(balance_a) * 2);
+            (self.tokens[1].address.clone(), U128(amount.as_u128()))
+        }
+    }
+
+    // Same formula as get_quote for any reserves and fee, ignoring the pool state
+    pub fn quote_custom(
+        &self,
+        reserve_in: U128,
+        reserve_out: U128,
+        amount_in: U128,
+        fee_bps: u16,
+    ) -> U128 {
+        require!(
+            u128::from(fee_bps) <= BPS_DENOMINATOR,
+            "Fee cannot exceed 10000 bps."
+        );
+        // An empty input side divides by zero, also when the fee rounds the input down to 0
+        if reserve_in.0 == 0 && Self::deduct_bps(amount_in.0, fee_bps) == 0 {
+            return U128(0);
+        }
+
+        U128(Self::quote_amount_out_at_fee(
+            reserve_in.0,
+            reserve_out.0,
+            amount_in.0,
+            fee_bps,
+        ))
+    }
+
+    // Price a swap of `amount_in` executes at, fee and price impact included
+    pub fn get_effective_price(&self, token_in: AccountId, amount_in: U128) -> U128 {
+        let token_in = self.get_token_index(token_in);
+        require!(
+            self.tokens[0].balance > 0 && self.tokens[1].balance > 0,
+            "Cannot quote an empty pool."
+        );
+        let token_out_amount = self.quote_amount_out(
+            self.tokens[token_in].balance,
+            self.tokens[1 - token_in].balance,
+            amount_in.0,
+        );
+        require!(token_out_amount > 0, "Cannot swap for 0 tokens.");
+
+        U128(self.get_price(token_in, amount_in.0, token_out_amount))
+    }
+
+    // Marginal price of token_in in units of the other token
+    pub fn get_spot_price(&self, token_in: AccountId) -> U128 {
+        let token_in = self.get_token_index(token_in);
+        let balance_in = self.tokens[token_in].balance;
+        let balance_out = self.tokens[1 - token_in].balance;
+        require!(
+            balance_in > 0 && balance_out > 0,
+            "Cannot compute the price for an empty pool."
+        );
+
+        U128(self.get_price(token_in, balance_in, balance_out))
+    }
+
+    // sqrt(reserve_a * reserve_b) in raw units, which grows with K and values pool shares
+    pub fn get_geometric_mean_price(&self) -> U128 {
+        U128(
+            (U256::from(self.tokens[0].balance) * U256::from(self.tokens[1].balance))
+                .integer_sqrt()
+                .as_u128(),
+        )
+    }
+
+    // Amounts of token A and token B worth `reference_amount` of the reference token at the
+    // spot price, e.g. to fill both inputs from one "worth" value
+    pub fn quote_reference(
+        &self,
+        reference_in_token: AccountId,
+        reference_amount: U128,
+    ) -> (U128, U128) {
+        let index = self.get_token_index(reference_in_token);
+        require!(
+            self.is_initialization_complete(),
+            "Pool metadata is not initialized."
+        );
+        let balance_a = self.tokens[0].balance;
+        let balance_b = self.tokens[1].balance;
+        require!(
+            balance_a > 0 && balance_b > 0,
+            "Cannot quote an empty pool."
+        );
+
+        let other_amount = (U256::from(reference_amount.0)
+            * U256::from(self.tokens[1 - index].balance)
+            / self.tokens[index].balance)
+            .as_u128();
+        if index == 0 {
+            (reference_amount, U128(other_amount))
+        } else {
+            (U128(other_amount), reference_amount)
+        }
+    }
+
+    pub fn get_market_overview(&self, reference_amount: U128) -> MarketOverview {
+        let balance_a = self.tokens[0].balance;
+        let balance_b = self.tokens[1].balance;
+        require!(
+            balance_a > 0 && balance_b > 0,
+            "Cannot quote an empty pool."
+        );
+
+        MarketOverview {
+            reserve_a: U128(balance_a),
+            reserve_b: U128(balance_b),
+            mid_price: U128(self.get_price(0, balance_a, balance_b)),
+            amount_out_a_to_b: U128(self.quote_amount_out(
+                balance_a,
+                balance_b,
+                reference_amount.0,
+            )),
+            amount_out_b_to_a: U128(self.quote_amount_out(
+                balance_b,
+                balance_a,
+                reference_amount.0,
+            )),
+            fee_bps: self.fee_bps,
+        }
+    }
+
+    // Apply hypothetical swaps to a copy of the balances and return the resulting spot price
+    // of token A in token B.
+    pub fn simulate_swap_sequence(&self, swaps: Vec<(AccountId, U128)>) -> U128 {
+        require!(
+            swaps.len() <= MAX_SIMULATED_SWAPS,
+            "Too many swaps to simulate."
+        );
+        // Swaps only grow the input side and never drain the output side, so no step divides by zero
+        require!(
+            self.tokens[0].balance > 0 && self.tokens[1].balance > 0,
+            "Cannot compute the price for an empty pool."
+        );
+
+        let mut balances = [self.tokens[0].balance, self.tokens[1].balance];
+        for (token, amount) in swaps {
+            let token_in = self.get_token_index(token);
+            let token_out = 1 - token_in;
+            require!(amount.0 > 0, "Amount must be positive.");
+
+            let token_out_amount =
+                self.quote_amount_out(balances[token_in], balances[token_out], amount.0);
+            balances[token_in] += amount.0;
+            balances[token_out] -= token_out_amount;
+        }
+
+        U128(self.get_price(0, balances[0], balances[1]))
+    }
+
+    #[private]
+    pub fn swap_callback(
+        &mut self,
+        sender_id: AccountId,
+        token_in: usize,
+        amount: Balance,
+        excess: Balance,
+        token_out_amount: Balance,
+        protocol_fee: Balance,
+        #[callback_result] call_result: Result<(), PromiseError>,
+    ) -> PromiseOrValue<U128> {
+        // Pauses are not checked here: the output is already transferred and must be committed
+        self.record_transfer_result(1 - token_in, &sender_id, call_result.is_ok());
+        if call_result.is_err() {
+            // Return the deposited tokens if the swap fails
+            log!("Transfering the swapped tokens failed. Recipient not registered with output token, or the token rejected the transfer.");
+            self.revert_swap(token_in, amount, token_out_amount, protocol_fee);
+            PromiseOrValue::Value((amount + excess).into())
+        } else {
+            self.commit_swap(&sender_id, token_in, amount, token_out_amount);
+
+            PromiseOrValue::Value(excess.into())
+        }
+    }
+
+    // A token that cannot answer storage_balance_of is not excused, the failure counts
+    #[private]
+    pub fn transfer_failure_callback(
+        &mut self,
+        index: usize,
+        #[callback_result] storage_balance: Result<Option<StorageBalance>, PromiseError>,
+    ) {
+        if !matches!(storage_balance, Ok(None)) {
+            self.record_transfer_failure(index);
+        }
+    }
+
+    #[private]
+    pub fn swap_forward_callback(
+        &mut self,
+        sender_id: AccountId,
+        receiver_id: AccountId,
+        token_in: usize,
+        amount: Balance,
+        excess: Balance,
+        token_out_amount: Balance,
+        protocol_fee: Balance,
+        #[callback_result] call_result: Result<U128, PromiseError>,
+    ) -> PromiseOrValue<U128> {
+        self.record_transfer_result(1 - token_in, &receiver_id, call_result.is_ok());
+        let used_amount = match call_result {
+            Ok(used_amount) => used_amount.0,
+            Err(_) => {
+                // Return the deposited tokens if the output could not be forwarded
+                log!("Forwarding the swapped tokens failed.");
+                self.revert_swap(token_in, amount, token_out_amount, protocol_fee);
+                return PromiseOrValue::Value((amount + excess).into());
+            }
+        };
+
+        self.commit_swap(&sender_id, token_in, amount, token_out_amount);
+
+        // The forward target returned part of the output to the AMM, pass it on to the sender
+        let unused_amount = token_out_amount.saturating_sub(used_amount);
+        if unused_amount > 0 {
+            ft_core_ext(self.tokens[1 - token_in].address.clone())
+                .with_static_gas(TGAS)
+                .with_attached_deposit(ONE_YOCTO)
+                .ft_transfer(sender_id.clone(), unused_amount.into(), None)
+                .then(
+                    Self::ext(env::current_account_id())
+                        .with_static_gas(TGAS)
+                        .forward_refund_callback(sender_id, 1 - token_in, unused_amount),
+                );
+        }
+
+        PromiseOrValue::Value(excess.into())
+    }
+
+    // If the sender cannot receive the unused output, it is kept as their pending deposit
+    #[private]
+    pub fn forward_refund_callback(
+        &mut self,
+        sender_id: AccountId,
+        index: usize,
+        amount: Balance,
+        #[callback_result] call_result: Result<(), PromiseError>,
+    ) {
+        if call_result.is_err() {
+            log!("Returning the unused forwarded tokens failed.");
+            self.credit_pending_deposit(&sender_id, index, amount);
+        }
+    }
+
+    #[private]
+    pub fn swap_split_callback(
+        &mut self,
+        sender_id: AccountId,
+        token_in: usize,
+        amount: Balance,
+        excess: Balance,
+        token_out_amount: Balance,
+        protocol_fee: Balance,
+        platform_amount: Balance,
+        #[callback_result] sender_result: Result<(), PromiseError>,
+        #[callback_result] platform_result: Result<(), PromiseError>,
+    ) -> PromiseOrValue<U128> {
+        let sender_amount = token_out_amount - platform_amount;
+        self.record_transfer_result(1 - token_in, &sender_id, sender_result.is_ok());
+        match (sender_result.is_ok(), platform_result.is_ok()) {
+            (true, true) => {
+                self.commit_swap(&sender_id, token_in, amount, token_out_amount);
+                PromiseOrValue::Value(excess.into())
+            }
+            (true, false) => {
+                // The platform fee stays in the pool
+                log!("Transfering the platform fee failed.");
+                self.revert_swap(token_in, 0, platform_amount, 0);
+                self.commit_swap(&sender_id, token_in, amount, sender_amount);
+                PromiseOrValue::Value(excess.into())
+            }
+            (false, true) => {
+                // Only the platform's part of the trade went through, refund the input for
+                // the sender's part. Trading less at the same price only favours the pool.
+                log!("Transfering the swapped tokens failed. Recipient not registered with output token, or the token rejected the transfer.");
+                let refund = (U256::from(amount) * U256::from(sender_amount)
+                    / U256::from(token_out_amount))
+                .as_u128();
+                let kept_protocol_fee = (U256::from(protocol_fee) * U256::from(amount - refund)
+                    / U256::from(amount))
+                .as_u128();
+                self.revert_swap(
+                    token_in,
+                    refund,
+                    sender_amount,
+                    protocol_fee - kept_protocol_fee,
+                );
+                self.commit_swap(&sender_id, token_in, amount - refund, platform_amount);
+                PromiseOrValue::Value((refund + excess).into())
+            }
+            (false, false) => {
+                log!("Transfering the swapped tokens failed. Recipient not registered with output token, or the token rejected the transfer.");
+                self.revert_swap(token_in, amount, token_out_amount, protocol_fee);
+                PromiseOrValue::Value((amount + excess).into())
+            }
+        }
+    }
+
+    #[private]
+    pub fn remove_liquidity_callback(
+        &mut self,
+        account_id: AccountId,
+        shares: U128,
+        amount_a: Balance,
+        amount_b: Balance,
+        #[callback_result] result_a: Result<(), PromiseError>,
+        #[callback_result] result_b: Result<(), PromiseError>,
+    ) {
+        match (result_a.is_ok(), result_b.is_ok()) {
+            (true, true) => {
+                self.record_event(events::log_remove_liquidity(
+                    &account_id,
+                    amount_a,
+                    amount_b,
+                    shares.0,
+                ));
+            }
+            (false, false) => {
+                // Nothing was withdrawn, restore the reserves and the shares
+                log!("Transfering the withdrawn tokens failed.");
+                self.update_oracle();
+                self.tokens[0].balance += amount_a;
+                self.tokens[1].balance += amount_b;
+                let account_shares = self.shares.get(&account_id).unwrap_or(0);
+                self.shares
+                    .insert(&account_id, &(account_shares + shares.0));
+                self.total_shares += shares.0;
+                self.invalidate_balance_cache();
+            }
+            (a_ok, _) => {
+                // Restoring the shares would pay out the delivered side twice, so the failed
+                // side is kept as a pending deposit the account can add back as liquidity
+                log!("Transfering the withdrawn tokens failed.");
+                if a_ok {
+                    self.credit_pending_deposit(&account_id, 1, amount_b);
+                } else {
+                    self.credit_pending_deposit(&account_id, 0, amount_a);
+                }
+            }
+        }
+    }
+}
+
+impl AMM {
+    fn get_token_index(&self, token: AccountId) -> usize {
+        let index = self
+            .tokens
+            .iter()
+            .position(|pool_token| pool_token.address == token);
+        require!(index.is_some(), "Token not supported.");
+        index.unwrap()
+    }
+
+    // Every owner call counts as activity for the inactivity pause
+    fn assert_owner(&mut self) {
+        require!(
+            env::predecessor_account_id() == self.owner,
+            "Only the owner can call this method."
+        );
+        self.last_owner_action_block = env::block_height();
+    }
+
+    // Accumulates the prices of the reserves about to change for the time since the last update
+    fn update_oracle(&mut self) {
+        let now = env::block_timestamp() / 1_000_000_000;
+        let elapsed = U256::from(now - self.last_update_timestamp);
+        let (balance_a, balance_b) = (self.tokens[0].balance, self.tokens[1].balance);
+        if balance_a > 0 && balance_b > 0 {
+            let price_0 = U256::from(balance_b) * U256::from(PRICE_PRECISION) / balance_a;
+            let price_1 = U256::from(balance_a) * U256::from(PRICE_PRECISION) / balance_b;
+            self.price_0_cumulative = self
+                .price_0_cumulative
+                .wrapping_add((price_0 * elapsed).low_u128());
+            self.price_1_cumulative = self
+                .price_1_cumulative
+                .wrapping_add((price_1 * elapsed).low_u128());
+        }
+        self.last_update_timestamp = now;
+    }
+
+    fn pause_if_inactive(&mut self) {
+        if self.get_blocks_until_inactivity_pause() == Some(0) && !self.paused {
+            log!("No owner activity within the inactivity window, pausing the pool.");
+            self.paused = true;
+        }
+    }
+
+    fn get_metadata_mut(&mut self, token: AccountId) -> &mut TokenMetadata {
+        let index = self.get_token_index(token);
+        require!(
+            self.tokens[index].metadata.is_some(),
+            "Metadata is not initialized!"
+        );
+        self.tokens[index].metadata.as_mut().unwrap()
+    }
+
+    fn owner_deposit(&mut self, token_in: usize, amount: Balance) {
+        if self.strict_init {
+            require!(
+                self.is_initialization_complete(),
+                "Pool metadata is not initialized."
+            );
+        }
+        self.update_oracle();
+        self.tokens[token_in].balance += amount;
+        self.invalidate_balance_cache();
+        self.record_event(events::log_deposit(&self.tokens[token_in].address, amount));
+
+        if !self.has_swapped {
+            self.assert_init_balanced();
+        }
+    }
+
+    // Holds one side of a liquidity deposit until the other side arrives. Returns the off-ratio
+    // excess of `token_in` to refund, the excess of the held side stays pending.
+    fn deposit_liquidity(
+        &mut self,
+        account_id: AccountId,
+        token_in: usize,
+        amount: Balance,
+    ) -> Balance {
+        let (mut amount_a, mut amount_b) = self.pending_deposits.get(&account_id).unwrap_or((0, 0));
+        if amount_a == 0 && amount_b == 0 && self.shares.get(&account_id).is_none() {
+            self.assert_storage_available();
+        }
+        if token_in == 0 {
+            amount_a += amount;
+        } else {
+            amount_b += amount;
+        }
+
+        if amount_a == 0 || amount_b == 0 {
+            self.set_pending_deposit(&account_id, (amount_a, amount_b));
+            return 0;
+        }
+
+        let (used_a, used_b) = self.add_liquidity(account_id.clone(), amount_a, amount_b);
+        let (excess_a, excess_b) = (amount_a - used_a, amount_b - used_b);
+        if token_in == 0 {
+            self.set_pending_deposit(&account_id, (0, excess_b));
+            excess_a
+        } else {
+            self.set_pending_deposit(&account_id, (excess_a, 0));
+            excess_b
+        }
+    }
+
+    fn assert_storage_available(&self) {
+        if let Some(max_storage_usage) = self.max_storage_usage {
+            require!(
+                env::storage_usage() < max_storage_usage,
+                "Storage cap reached, new liquidity providers are blocked."
+            );
+        }
+    }
+
+    // Stores the account's pending deposit and keeps the per-token totals in step
+    fn set_pending_deposit(&mut self, account_id: &AccountId, pending: (Balance, Balance)) {
+        let (old_a, old_b) = self.pending_deposits.get(account_id).unwrap_or((0, 0));
+        self.pending_totals[0] = self.pending_totals[0] - old_a + pending.0;
+        self.pending_totals[1] = self.pending_totals[1] - old_b + pending.1;
+        if pending == (0, 0) {
+            self.pending_deposits.remove(account_id);
+        } else {
+            self.pending_deposits.insert(account_id, &pending);
+        }
+    }
+
+    // Adds tokens the AMM could not send to the account to its pending deposit, from where they
+    // can be added as liquidity or recovered with `withdraw_pending`
+    fn credit_pending_deposit(&mut self, account_id: &AccountId, index: usize, amount: Balance) {
+        let (mut amount_a, mut amount_b) = self.pending_deposits.get(account_id).unwrap_or((0, 0));
+        if index == 0 {
+            amount_a += amount;
+        } else {
+            amount_b += amount;
+        }
+        self.set_pending_deposit(account_id, (amount_a, amount_b));
+    }
+
+    // The part of a deposit that matches the pool ratio, as in Uniswap V2's router: the limiting
+    // side is used in full and the other side is quoted from it, rounded up in favor of the pool
+    fn match_pool_ratio(&self, amount_a: Balance, amount_b: Balance) -> (Balance, Balance) {
+        let balance_a = U256::from(self.tokens[0].balance);
+        let balance_b = U256::from(self.tokens[1].balance);
+        if balance_a.is_zero() {
+            return (amount_a, amount_b);
+        }
+
+        let optimal_b = ((U256::from(amount_a) * balance_b + balance_a - 1) / balance_a).as_u128();
+        if optimal_b <= amount_b {
+            (amount_a, optimal_b)
+        } else {
+            let optimal_a =
+                ((U256::from(amount_b) * balance_a + balance_b - 1) / balance_b).as_u128();
+            (optimal_a, amount_b)
+        }
+    }
+
+    // Mints min(amount_a * total / reserve_a, amount_b * total / reserve_b) shares, or
+    // sqrt(amount_a * amount_b) - MINIMUM_LIQUIDITY for the first deposit. Only the part
+    // matching the pool ratio is added, the used amounts are returned.
+    fn add_liquidity(
+        &mut self,
+        account_id: AccountId,
+        amount_a: Balance,
+        amount_b: Balance,
+    ) -> (Balance, Balance) {
+        self.assert_funded_on_both_sides();
+        let (amount_a, amount_b) = self.match_pool_ratio(amount_a, amount_b);
+        let balance_a = self.tokens[0].balance;
+        let balance_b = self.tokens[1].balance;
+
+        // The reserves seeded by the owner so far are credited as the owner's shares
+        if self.total_shares == 0 && balance_a > 0 {
+            let seeded_shares = Self::geometric_mean(balance_a, balance_b);
+            require!(
+                seeded_shares > MINIMUM_LIQUIDITY,
+                "The owner's reserves are too small to mint shares."
+            );
+            self.shares
+                .insert(&self.owner, &(seeded_shares - MINIMUM_LIQUIDITY));
+            self.total_shares = seeded_shares;
+        }
+
+        let minted = if self.total_shares == 0 {
+            let first_shares = Self::geometric_mean(amount_a, amount_b);
+            require!(
+                first_shares > MINIMUM_LIQUIDITY,
+                "Deposit is too small to mint shares."
+            );
+            self.total_shares = MINIMUM_LIQUIDITY;
+            first_shares - MINIMUM_LIQUIDITY
+        } else {
+            let total_shares = U256::from(self.total_shares);
+            (U256::from(amount_a) * total_shares / balance_a)
+                .min(U256::from(amount_b) * total_shares / balance_b)
+                .as_u128()
+        };
+        require!(minted > 0, "Deposit is too small to mint shares.");
+
+        self.update_oracle();
+        self.tokens[0].balance += amount_a;
+        self.tokens[1].balance += amount_b;
+        self.invalidate_balance_cache();
+
+        let shares = self.shares.get(&account_id).unwrap_or(0) + minted;
+        self.shares.insert(&account_id, &shares);
+        self.total_shares += minted;
+
+        self.record_event(events::log_add_liquidity(
+            &account_id,
+            amount_a,
+            amount_b,
+            minted,
+        ));
+        (amount_a, amount_b)
+    }
+
+    fn assert_funded_on_both_sides(&self) {
+        require!(
+            (self.tokens[0].balance == 0) == (self.tokens[1].balance == 0),
+            "Cannot add liquidity to a pool funded on one side."
+        );
+    }
+
+    fn geometric_mean(amount_a: Balance, amount_b: Balance) -> Balance {
+        (U256::from(amount_a) * U256::from(amount_b))
+            .integer_sqrt()
+            .as_u128()
+    }
+
+    // Reject seeding a pool with extreme prices. The check is skipped until both reserves are
+    // funded and both decimals are known.
+    fn assert_init_balanced(&self) {
+        let max_imbalance = match self.max_init_imbalance_bps {
+            Some(max_imbalance) => max_imbalance,
+            None => return,
+        };
+        let (metadata_a, metadata_b) = match (&self.tokens[0].metadata, &self.tokens[1].metadata) {
+            (Some(metadata_a), Some(metadata_b)) => (metadata_a, metadata_b),
+            _ => return,
+        };
+        if self.tokens[0].balance == 0 || self.tokens[1].balance == 0 {
+            return;
+        }
+
+        // Compare balance_a / 10^decimals_a against balance_b / 10^decimals_b without dividing
+        let scaled_a = U256::from(self.tokens[0].balance) * U256::exp10(metadata_b.decimals.into());
+        let scaled_b = U256::from(self.tokens[1].balance) * U256::exp10(metadata_a.decimals.into());
+        let (larger, smaller) = if scaled_a > scaled_b {
+            (scaled_a, scaled_b)
+        } else {
+            (scaled_b, scaled_a)
+        };
+
+        require!(
+            larger * U256::from(BPS_DENOMINATOR) <= smaller * U256::from(max_imbalance),
+            "Deposit creates an imbalanced initial ratio."
+        );
+    }
+
+    fn swap(
+        &mut self,
+        sender_id: AccountId,
+        token_in: usize,
+        amount: Balance,
+        excess: Balance,
+        swap_msg: SwapMsg,
+    ) -> PromiseOrValue<U128> {
+        // Panicking makes the token refund the whole transfer
+        require!(
+            self.tokens[0].balance > 0 && self.tokens[1].balance > 0,
+            "Pool not initialized."
+        );
+        require!(!self.swaps_paused, "Swaps are paused.");
+        require!(
+            self.is_initialization_complete(),
+            "Pool metadata is not initialized."
+        );
+        if let Some(deadline) = swap_msg.deadline {
+            if env::block_timestamp() > deadline.0 {
+                log!("Swap deadline has passed.");
+                return PromiseOrValue::Value((amount + excess).into());
+            }
+        }
+        // Refresh in the background, the swap keeps using the current metadata
+        for index in 0..self.tokens.len() {
+            if self.is_metadata_stale(index) && !self.tokens[index].metadata_refresh_pending {
+                self.tokens[index].metadata_refresh_pending = true;
+                self.update_metadata(self.tokens[index].address.clone());
+            }
+        }
+
+        let platform_fee = match (swap_msg.platform_fee_bps, swap_msg.platform_account.clone()) {
+            (Some(fee_bps), Some(account_id)) => {
+                require!(
+                    fee_bps <= MAX_PLATFORM_FEE_BPS,
+                    "Platform fee cannot exceed 500 bps."
+                );
+                require!(
+                    swap_msg.forward_to.is_none(),
+                    "Platform fees cannot be combined with forward_to."
+                );
+                Some((fee_bps, account_id))
+            }
+            (None, None) => None,
+            _ => env::panic_str("Platform fee and platform account must be set together."),
+        };
+
+        let token_out = 1 - token_in;
+        require!(
+            !self.tokens[token_out].output_paused,
+            "Swaps into this token are paused."
+        );
+
+        // The protocol's part of the fee is kept out of the reserves
+        let fee_bps = self.get_account_fee_bps(sender_id.clone());
+        let protocol_fee = self.get_protocol_fee_amount(amount, fee_bps);
+        let new_balance_in = self.tokens[token_in].balance + amount - protocol_fee;
+        let token_out_amount = Self::quote_amount_out_at_fee(
+            self.tokens[token_in].balance,
+            self.tokens[token_out].balance,
+            amount,
+            fee_bps,
+        );
+
+        require!(
+            token_out_amount <= self.tokens[token_out].balance,
+            "Not enough funds to complete the trade."
+        );
+        require!(token_out_amount > 0, "Cannot swap for 0 tokens.");
+
+        if token_out_amount < self.get_min_amount_out(token_in, amount, fee_bps, &swap_msg) {
+            // Return the deposited tokens if the price moved too much
+            log!("Slippage limit exceeded.");
+            return PromiseOrValue::Value((amount + excess).into());
+        }
+        if !self.meets_min_execution_rate(token_in, amount, token_out_amount) {
+            log!("Execution rate below the pool minimum.");
+            return PromiseOrValue::Value((amount + excess).into());
+        }
+        if let Some(max_impact_bps) = swap_msg.max_impact_bps {
+            require!(
+                u128::from(max_impact_bps) <= BPS_DENOMINATOR,
+                "Price impact cannot exceed 10000 bps."
+            );
+            let impact_bps = self.get_price_impact_bps(token_in, amount, fee_bps, token_out_amount);
+            if impact_bps > u128::from(max_impact_bps) {
+                log!("Price impact limit exceeded.");
+                return PromiseOrValue::Value((amount + excess).into());
+            }
+        }
+        if let Some(max_total_cost_bps) = swap_msg.max_total_cost_bps {
+            require!(
+                u128::from(max_total_cost_bps) <= BPS_DENOMINATOR,
+                "Total cost cannot exceed 10000 bps."
+            );
+            let cost_bps = u128::from(fee_bps)
+                + self.get_price_impact_bps(token_in, amount, fee_bps, token_out_amount);
+            if cost_bps > u128::from(max_total_cost_bps) {
+                log!("Total cost limit exceeded.");
+                return PromiseOrValue::Value((amount + excess).into());
+            }
+        }
+
+        // The fee and rounding favor the pool, so K can only grow. Anything else is an
+        // accounting bug, caught here while the input can still be refunded.
+        let new_balance_out = self.tokens[token_out].balance - token_out_amount;
+        require!(
+            U256::from(new_balance_in) * U256::from(new_balance_out)
+                >= U256::from(self.tokens[token_in].balance)
+                    * U256::from(self.tokens[token_out].balance),
+            "Swap decreased the pool invariant."
+        );
+        // The reserves move now, so overlapping swaps and withdrawals price off what is left.
+        // The callback undoes this if the output cannot be delivered.
+        self.apply_swap(token_in, amount, token_out_amount, protocol_fee);
+
+        if self.sandwich_detection {
+            self.record_swap(&sender_id, token_in);
+        }
+        if self.is_large_swap(token_in, amount) {
+            self.record_event(events::log_large_swap(
+                &sender_id,
+                &self.tokens[token_in].address,
+                amount,
+                token_out_amount,
+            ));
+        }
+
+        let token_out_contract =
+            ft_core_ext(self.tokens[token_out].address.clone()).with_attached_deposit(ONE_YOCTO);
+
+        if let Some((fee_bps, platform_account)) = platform_fee {
+            let platform_contract = ft_core_ext(self.tokens[token_out].address.clone())
+                .with_attached_deposit(ONE_YOCTO);
+            let platform_amount =
+                (U256::from(token_out_amount) * U256::from(fee_bps) / BPS_DENOMINATOR).as_u128();
+            return token_out_contract
+                .with_static_gas(self.transfer_gas)
+                .ft_transfer(
+                    sender_id.clone(),
+                    (token_out_amount - platform_amount).into(),
+                    None,
+                )
+                .and(
+                    platform_contract
+                        .with_static_gas(self.transfer_gas)
+                        .ft_transfer(platform_account, platform_amount.into(), None),
+                )
+                .then(
+                    Self::ext(env::current_account_id())
+                        .with_static_gas(SWAP_CALLBACK_GAS)
+                        .swap_split_callback(
+                            sender_id,
+                            token_in,
+                            amount,
+                            excess,
+                            token_out_amount,
+                            protocol_fee,
+                            platform_amount,
+                        ),
+                )
+                .into();
+        }
+
+        match swap_msg.forward_to {
+            Some(receiver_id) => token_out_contract
+                .with_static_gas(FT_TRANSFER_CALL_GAS)
+                .ft_transfer_call(
+                    receiver_id.clone(),
+                    token_out_amount.into(),
+                    None,
+                    swap_msg.forward_msg.unwrap_or_default(),
+                )
+                .then(
+                    Self::ext(env::current_account_id())
+                        .with_static_gas(FORWARD_CALLBACK_GAS)
+                        .swap_forward_callback(
+                            sender_id,
+                            receiver_id,
+                            token_in,
+                            amount,
+                            excess,
+                            token_out_amount,
+                            protocol_fee,
+                        ),
+                )
+                .into(),
+            None => token_out_contract
+                .with_static_gas(self.transfer_gas)
+                .ft_transfer(sender_id.clone(), token_out_amount.into(), None)
+                .then(
+                    Self::ext(env::current_account_id())
+                        .with_static_gas(SWAP_CALLBACK_GAS)
+                        .swap_callback(
+                            sender_id,
+                            token_in,
+                            amount,
+                            excess,
+                            token_out_amount,
+                            protocol_fee,
+                        ),
+                )
+                .into(),
+        }
+    }
+
+    // Pauses swaps if the actual balances fall short of the reserves beyond the tolerance
+    fn check_drift(&mut self, balance_a: Balance, balance_b: Balance) -> bool {
+        let max_drift_bps = match self.max_drift_bps {
+            Some(max_drift_bps) => u128::from(max_drift_bps),
+            None => return true,
+        };
+
+        // Accrued protocol fees and pending deposits are held on top of the reserves
+        let within_tolerance = [balance_a, balance_b]
+            .iter()
+            .enumerate()
+            .zip(self.tokens.iter())
+            .all(|((index, actual), token)| {
+                let available = actual
+                    .saturating_sub(self.protocol_fees[index])
+                    .saturating_sub(self.pending_totals[index]);
+                let shortfall = U256::from(token.balance.saturating_sub(available));
+                shortfall * U256::from(BPS_DENOMINATOR)
+                    <= U256::from(token.balance) * U256::from(max_drift_bps)
+            });
+
+        if !within_tolerance {
+            log!("Reserves drifted from the token balances, pausing swaps.");
+            self.swaps_paused = true;
+        }
+        within_tolerance
+    }
+
+    // A failure only counts once the recipient turns out to be registered with the token, so
+    // swappers without storage on the output token cannot pause it
+    fn record_transfer_result(&mut self, index: usize, recipient: &AccountId, success: bool) {
+        if success {
+            self.tokens[index].transfer_failures = 0;
+            return;
+        }
+
+        ext_storage::ext(self.tokens[index].address.clone())
+            .with_static_gas(TRANSFER_FAILURE_CHECK_GAS)
+            .storage_balance_of(recipient.clone())
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(TRANSFER_FAILURE_CHECK_GAS)
+                    .transfer_failure_callback(index),
+            );
+    }
+
+    fn record_transfer_failure(&mut self, index: usize) {
+        let token = &mut self.tokens[index];
+        token.transfer_failures += 1;
+        if let Some(max_failures) = self.max_transfer_failures {
+            if token.transfer_failures >= max_failures && !token.output_paused {
+                token.output_paused = true;
+                let event = events::log_output_paused(&token.address, token.transfer_failures);
+                self.record_event(event);
+            }
+        }
+    }
+
+    fn record_event(&mut self, event: String) {
+        self.recent_events
+            .insert(&(self.event_count % RECENT_EVENTS_SIZE), &event);
+        self.event_count += 1;
+    }
+
+    fn cache_balances(&mut self, balance_a: Balance, balance_b: Balance) {
+        let timestamp = env::block_timestamp();
+        self.tokens[0].cached_balance = Some((balance_a, timestamp));
+        self.tokens[1].cached_balance = Some((balance_b, timestamp));
+    }
+
+    fn invalidate_balance_cache(&mut self) {
+        for token in self.tokens.iter_mut() {
+            token.cached_balance = None;
+        }
+    }
+
+    fn get_fresh_balance(&self, index: usize) -> Option<Balance> {
+        match self.tokens[index].cached_balance {
+            Some((balance, timestamp))
+                if env::block_timestamp() <= timestamp + BALANCE_CACHE_TTL =>
+            {
+                Some(balance)
+            }
+            _ => None,
+        }
+    }
+
+    fn is_metadata_stale(&self, index: usize) -> bool {
+        match self.metadata_ttl_blocks {
+            Some(ttl) => {
+                self.tokens[index].metadata.is_some()
+                    && env::block_height() > self.tokens[index].last_metadata_block + ttl
+            }
+            None => false,
+        }
+    }
+
+    fn meets_min_execution_rate(
+        &self,
+        token_in: usize,
+        amount_in: Balance,
+        amount_out: Balance,
+    ) -> bool {
+        match self.min_execution_rate {
+            Some(min_rate) => {
+                let decimals_in = self.tokens[token_in].metadata.as_ref().unwrap().decimals;
+                let decimals_out = self.tokens[1 - token_in]
+                    .metadata
+                    .as_ref()
+                    .unwrap()
+                    .decimals;
+                // (amount_out / 10^decimals_out) / (amount_in / 10^decimals_in) >= min_rate / 10^18
+                U256::from(amount_out)
+                    * U256::exp10(decimals_in as usize)
+                    * U256::from(PRICE_PRECISION)
+                    >= U256::from(min_rate)
+                        * U256::from(amount_in)
+                        * U256::exp10(decimals_out as usize)
+            }
+            None => true,
+        }
+    }
+
+    fn is_large_swap(&self, token_in: usize, amount: Balance) -> bool {
+        match self.large_swap_threshold {
+            Some(threshold) => {
+                let decimals = self.tokens[token_in].metadata.as_ref().unwrap().decimals;
+                U256::from(amount) * U256::from(PRICE_PRECISION)
+                    > U256::from(threshold) * U256::exp10(decimals as usize)
+            }
+            None => false,
+        }
+    }
+
+    // Flags a swap that closes a front-run of the previous swap in the same block. Related
+    // accounts can only be matched by account id.
+    fn record_swap(&mut self, sender_id: &AccountId, token_in: usize) {
+        if self.recent_swaps.len() == SANDWICH_WINDOW {
+            self.recent_swaps.remove(0);
+        }
+        self.recent_swaps.push(RecentSwap {
+            block_height: env::block_height(),
+            sender_id: sender_id.clone(),
+            token_in,
+        });
+
+        if let [front, victim, back] = &self.recent_swaps[..] {
+            if front.block_height == back.block_height
+                && victim.block_height == back.block_height
+                && front.sender_id == back.sender_id
+                && front.sender_id != victim.sender_id
+                && front.token_in == victim.token_in
+                && back.token_in != front.token_in
+            {
+                let event = events::log_possible_sandwich(
+                    back.block_height,
+                    &back.sender_id,
+                    &victim.sender_id,
+                );
+                self.record_event(event);
+            }
+        }
+    }
+
+    // Swaps are only logged once the output transfer succeeded. The reserves may have moved
+    // since the swap was priced, so the amounts are applied as deltas.
+    // Moves the reserves for a priced swap, before its output is sent
+    fn apply_swap(
+        &mut self,
+        token_in: usize,
+        amount_in: Balance,
+        amount_out: Balance,
+        protocol_fee: Balance,
+    ) {
+        let (balance_a_before, balance_b_before) = (self.tokens[0].balance, self.tokens[1].balance);
+        self.update_oracle();
+        self.tokens[token_in].balance += amount_in - protocol_fee;
+        self.protocol_fees[token_in] += protocol_fee;
+        self.tokens[1 - token_in].balance -= amount_out;
+        self.last_swap_reserves = Some((
+            balance_a_before,
+            balance_b_before,
+            self.tokens[0].balance,
+            self.tokens[1].balance,
+        ));
+        self.invalidate_balance_cache();
+    }
+
+    // Undoes `apply_swap` for the part of a swap whose output was not delivered. Withdrawals
+    // in the meantime may have taken a share of the credited input, so that side saturates.
+    fn revert_swap(
+        &mut self,
+        token_in: usize,
+        amount_in: Balance,
+        amount_out: Balance,
+        protocol_fee: Balance,
+    ) {
+        self.update_oracle();
+        self.tokens[token_in].balance = self.tokens[token_in]
+            .balance
+            .saturating_sub(amount_in - protocol_fee);
+        self.protocol_fees[token_in] = self.protocol_fees[token_in].saturating_sub(protocol_fee);
+        self.tokens[1 - token_in].balance += amount_out;
+        self.invalidate_balance_cache();
+    }
+
+    // Records a delivered swap, the reserves were already moved by `apply_swap`
+    fn commit_swap(
+        &mut self,
+        sender_id: &AccountId,
+        token_in: usize,
+        amount_in: Balance,
+        amount_out: Balance,
+    ) {
+        self.has_swapped = true;
+
+        let decimals = self.tokens[token_in].metadata.as_ref().unwrap().decimals;
+        let normalized_amount_in = (U256::from(amount_in) * U256::from(PRICE_PRECISION)
+            / U256::exp10(decimals as usize))
+        .as_u128();
+        let volume = self.volume.get(sender_id).unwrap_or(0);
+        self.volume
+            .insert(sender_id, &volume.saturating_add(normalized_amount_in));
+
+        let event = events::log_swap(
+            sender_id,
+            &self.tokens[token_in].address,
+            &self.tokens[1 - token_in].address,
+            amount_in,
+            amount_out,
+        );
+        self.record_event(event);
+    }
+
+    fn get_protocol_fee_amount(&self, amount_in: Balance, fee_bps: u16) -> Balance {
+        let fee = U256::from(amount_in) * U256::from(fee_bps) / BPS_DENOMINATOR;
+        (fee * U256::from(self.protocol_fee_bps) / BPS_DENOMINATOR).as_u128()
+    }
+
+    // Output after the fee is taken from the input
+    fn quote_amount_out(
+        &self,
+        balance_in: Balance,
+        balance_out: Balance,
+        amount_in: Balance,
+    ) -> Balance {
+        Self::quote_amount_out_at_fee(balance_in, balance_out, amount_in, self.fee_bps)
+    }
+
+    // Output tokens per input token, in whole tokens scaled by 10^18. Every price view uses this
+    // orientation and unit, like `min_execution_rate`.
+    fn get_price(&self, token_in: usize, amount_in: Balance, amount_out: Balance) -> Balance {
+        require!(
+            self.is_initialization_complete(),
+            "Pool metadata is not initialized."
+        );
+        let decimals_in = self.tokens[token_in].metadata.as_ref().unwrap().decimals;
+        let decimals_out = self.tokens[1 - token_in]
+            .metadata
+            .as_ref()
+            .unwrap()
+            .decimals;
+        (U256::from(amount_out) * U256::exp10(decimals_in as usize) * U256::from(PRICE_PRECISION)
+            / (U256::from(amount_in) * U256::exp10(decimals_out as usize)))
+        .as_u128()
+    }
+
+    fn get_quote_fee_bps(&self, account_id: Option<AccountId>) -> u16 {
+        account_id.map_or(self.fee_bps, |account_id| {
+            self.get_account_fee_bps(account_id)
+        })
+    }
+
+    fn quote_amount_out_at_fee(
+        balance_in: Balance,
+        balance_out: Balance,
+        amount_in: Balance,
+        fee_bps: u16,
+    ) -> Balance {
+        let amount_in_after_fee = (U256::from(amount_in)
+            * U256::from(BPS_DENOMINATOR - u128::from(fee_bps))
+            / BPS_DENOMINATOR)
+            .as_u128();
+        Self::compute_amount_out(balance_in, balance_out, amount_in_after_fee)
+    }
+
+    fn compute_amount_out(
+        balance_in: Balance,
+        balance_out: Balance,
+        amount_in: Balance,
+    ) -> Balance {
+        // Avoid multiplication overflow by using U256
+        ((U256::from(balance_out) * U256::from(amount_in)) / (balance_in + amount_in)).as_u128()
+    }
+
+    // (spot output - output) / spot output in basis points, for the input after the fee
+    fn get_price_impact_bps(
+        &self,
+        token_in: usize,
+        amount_in: Balance,
+        fee_bps: u16,
+        amount_out: Balance,
+    ) -> u128 {
+        let amount_in_after_fee = U256::from(amount_in)
+            * U256::from(BPS_DENOMINATOR - u128::from(fee_bps))
+            / BPS_DENOMINATOR;
+        let spot_amount_out = U256::from(self.tokens[1 - token_in].balance) * amount_in_after_fee
+            / self.tokens[token_in].balance;
+        if spot_amount_out.is_zero() {
+            return 0;
+        }
+
+        (spot_amount_out.saturating_sub(U256::from(amount_out)) * U256::from(BPS_DENOMINATOR)
+            / spot_amount_out)
+            .as_u128()
+    }
+
+    // The strictest of the absolute and the relative bounds given in the swap msg. The relative
+    // bound is taken from the quote at the fee the swap pays.
+    fn get_min_amount_out(
+        &self,
+        token_in: usize,
+        amount: Balance,
+        fee_bps: u16,
+        swap_msg: &SwapMsg,
+    ) -> Balance {
+        let mut min_amount_out = swap_msg.min_amount_out.map_or(0, |min| min.0);
+
+        if let Some(max_slippage_bps) = swap_msg.max_slippage_bps {
+            require!(
+                u128::from(max_slippage_bps) <= BPS_DENOMINATOR,
+                "Slippage cannot exceed 10000 bps."
+            );
+            require!(
+                self.tokens[token_in].balance > 0,
+                "Cannot compute slippage for an empty pool."
+            );
+            let quoted_amount_out = Self::quote_amount_out_at_fee(
+                self.tokens[token_in].balance,
+                self.tokens[1 - token_in].balance,
+                amount,
+                fee_bps,
+            );
+            min_amount_out =
+                min_amount_out.max(Self::deduct_bps(quoted_amount_out, max_slippage_bps));
+        }
+
+        if let Some(max_value_loss_bps) = swap_msg.max_value_loss_bps {
+            require!(
+                u128::from(max_value_loss_bps) <= BPS_DENOMINATOR,
+                "Value loss cannot exceed 10000 bps."
+            );
+            let input_value = self.get_spot_amount_out(token_in, amount);
+            min_amount_out = min_amount_out.max(Self::deduct_bps(input_value, max_value_loss_bps));
+        }
+
+        min_amount_out
+    }
+
+    // The input valued in output tokens at the current spot price, before fees and price impact
+    fn get_spot_amount_out(&self, token_in: usize, amount: Balance) -> Balance {
+        require!(
+            self.tokens[token_in].balance > 0,
+            "Cannot compute slippage for an empty pool."
+        );
+        (U256::from(self.tokens[1 - token_in].balance) * U256::from(amount)
+            / self.tokens[token_in].balance)
+            .as_u128()
+    }
+
+    fn deduct_bps(amount: Balance, bps: u16) -> Balance {
+        (U256::from(amount) * U256::from(BPS_DENOMINATOR - u128::from(bps)) / BPS_DENOMINATOR)
+            .as_u128()
+    }
+}
+
+#[near_bindgen]
+impl FungibleTokenReceiver for AMM {
+    fn ft_on_transfer(
+        &mut self,
+        sender_id: AccountId,
+        amount: U128,
+        msg: String,
+    ) -> PromiseOrValue<U128> {
+        // Only the pool tokens can be deposited
+        let token_in: usize = self.get_token_index(env::predecessor_account_id());
+
+        let amount: Balance = amount.into();
+        require!(amount > 0, "Amount must be positive.");
+
+        // Only accept up to the deposit cap and refund the rest
+        let excess = match self.tokens[token_in].max_single_deposit {
+            Some(max_deposit) if amount > max_deposit => amount - max_deposit,
+            _ => 0,
+        };
+        let amount = amount - excess;
+
+        if sender_id == self.owner && (!self.owner_can_swap || msg.is_empty()) {
+            // sender_id is whatever the token contract reports, so a non-standard token could
+            // pass the owner for anyone. Crediting reserves also needs the owner's signature.
+            require!(
+                env::signer_account_id() == self.owner,
+                "Owner deposits must be signed by the owner."
+            );
+            self.last_owner_action_block = env::block_height();
+            let refund = if self.total_shares == 0 {
+                self.owner_deposit(token_in, amount);
+                0
+            } else {
+                // A one-sided owner deposit would be a donation to the liquidity providers
+                self.deposit_liquidity(sender_id, token_in, amount)
+            };
+            return PromiseOrValue::Value(near_sdk::json_types::U128(excess + refund));
+        }
+
+        // While paused the reserves can be swept by `emergency_withdraw`, so liquidity deposits
+        // are refunded like swaps
+        let swap_msg = SwapMsg::parse(&msg);
+        self.pause_if_inactive();
+        if self.paused {
+            log!("The pool is paused.");
+            PromiseOrValue::Value(near_sdk::json_types::U128(amount + excess))
+        } else if swap_msg.action == Some(TransferAction::AddLiquidity) {
+            let refund = self.deposit_liquidity(sender_id, token_in, amount);
+            PromiseOrValue::Value(near_sdk::json_types::U128(excess + refund))
+        } else if !self.is_allowed_swapper(sender_id.clone()) {
+            log!("The sender is not allowed to swap in this pool.");
+            PromiseOrValue::Value(near_sdk::json_types::U128(amount + excess))
+        } else {
+            self.swap(sender_id, token_in, amount, excess, swap_msg)
+        }
+    }
+}
+
+/*
+
+near call dev-1672856405734-99444394530569 new '{"owner": "ammdev.testnet", "token_a": "ft.predeployed.examples.testnet", "token_b": "ft.predeployed.examples.testnet"}' --account-id ammdev.testnet --gas=300000000000000
+
+near call ft.predeployed.examples.testnet storage_deposit '{"account_id": "dev-1672856405734-99444394530569"}' --accountId ammdev.testnet --amount 0.00125
+
+near call dev-1672856405734-99444394530569 get_metadata '{"ind": 0}' --account-id ammdev.testnet
+near call dev-1672856405734-99444394530569 get_balance '{"ind": 0}' --account-id ammdev.testnet
+
+near call ft.predeployed.examples.testnet ft_transfer '{"receiver_id": "'dev-1672856405734-99444394530569'", "amount": "1000"}' --accountId ammdev.testnet --depositYocto 1
+near call ft.predeployed.examples.testnet ft_transfer_call '{"receiver_id": "'dev-1672856405734-99444394530569'", "amount": "1000", "msg": ""}' --accountId ammdev.testnet --depositYocto 1
+near view ft.predeployed.examples.testnet ft_balance_of '{"account_id": "'dev-1672856405734-99444394530569'"}'  --account-id ammdev.testnet
+
+near create-account bob.ammdev.testnet --masterAccount ammdev.testnet --initialBalance 10
+near call ft.predeployed.examples.testnet storage_deposit '' --accountId bob.ammdev.testnet --amount 0.00125
+
+near view ft.predeployed.examples.testnet ft_balance_of '{"account_id": "'ammdev.testnet'"}'  --account-id ammdev.testnet
+near call ft.predeployed.examples.testnet ft_mint '{"account_id": "'ammdev.testnet'", "amount": "10000000000000000000000"}' --accountId ammdev.testnet
+
+near call ft.predeployed.examples.testnet ft_transfer '{"receiver_id": "'bob.ammdev.testnet'", "amount": "19"}' --accountId ammdev.testnet --depositYocto 1
+
+*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_sdk::test_utils::{get_logs, VMContextBuilder};
+    use near_sdk::{serde_json, testing_env, VMContext};
+
+    fn get_owner_ft_transfer_context(
+        signer: AccountId,
+        predecessor_account_id: AccountId,
+        is_view: bool,
+    ) -> VMContext {
+        VMContextBuilder::new()
+            .signer_account_id(signer)
+            .predecessor_account_id(predecessor_account_id)
+            .is_view(is_view)
+            .build()
+    }
+
+    fn assert_refunded(result: PromiseOrValue<U128>, amount: Balance) {
+        match result {
+            PromiseOrValue::Value(refund) => assert_eq!(refund, near_sdk::json_types::U128(amount)),
+            PromiseOrValue::Promise(_) => panic!("Expected a refund, got a promise."),
+        }
+    }
+
+    fn assert_swapped(result: PromiseOrValue<U128>) {
+        assert!(
+            matches!(result, PromiseOrValue::Promise(_)),
+            "Expected a swap, got a refund."
+        );
+    }
+
+    // 10 TA and 100 TB
+    // Fee-free, so the expected amounts follow the plain constant-product formula
+    fn funded_amm() -> AMM {
+        let mut amm = AMM::new(owner(), token_a(), token_b(), None);
+        amm.fee_bps = 0;
+        amm.tokens[0].metadata = Some(token_a_metadata());
+        amm.tokens[0].balance = 1_000_000_000;
+        amm.tokens[1].metadata = Some(token_b_metadata());
+        amm.tokens[1].balance = 1_000_000_000_000_000_000;
+        amm
+    }
+
+    fn owner() -> AccountId {
+        "owner.testnet".to_string().parse().unwrap()
+    }
+
+    fn alice() -> AccountId {
+        "alice.testnet".to_string().parse().unwrap()
+    }
+
+    fn bob() -> AccountId {
+        "bob.testnet".to_string().parse().unwrap()
+    }
+
+    fn token_a() -> AccountId {
+        "token_a.testnet".to_string().parse().unwrap()
+    }
+
+    fn token_a_metadata() -> TokenMetadata {
+        TokenMetadata::new(
+            "token_a".to_string().parse().unwrap(),
+            "TA".to_string().parse().unwrap(),
+            8,
+        )
+    }
+
+    fn token_b() -> AccountId {
+        "token_b.testnet".to_string().parse().unwrap()
+    }
+
+    fn token_b_metadata() -> TokenMetadata {
+        TokenMetadata::new(
+            "token_b".to_string().parse().unwrap(),
+            "TB".to_string().parse().unwrap(),
+            16,
+        )
+    }
+
+    #[test]
+    fn test_init() {
+        let amm = AMM::new(owner(), token_a(), token_b(), None);
+        assert_eq!(amm.owner, owner());
+        assert_eq!(amm.tokens[0].address, token_a());
+        assert_eq!(amm.tokens[1].address, token_b());
+    }
+
+    #[test]
+    fn test_transfer_ownership() {
+        let mut amm = AMM::new(owner(), token_a(), token_b(), None);
+        testing_env!(get_owner_ft_transfer_context(owner(), owner(), false));
+        amm.propose_new_owner(alice());
+        assert_eq!(amm.get_owner(), owner());
+        assert_eq!(amm.get_pending_owner(), Some(alice()));
+
+        testing_env!(get_owner_ft_transfer_context(alice(), alice(), false));
+        amm.accept_ownership();
+        assert_eq!(amm.get_owner(), alice());
+        assert_eq!(amm.get_pending_owner(), None);
+        amm.set_fee_bps(10);
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the owner can call this method.")]
+    fn test_propose_new_owner_not_owner() {
+        let mut amm = AMM::new(owner(), token_a(), token_b(), None);
+        testing_env!(get_owner_ft_transfer_context(alice(), alice(), false));
+        amm.propose_new_owner(alice());
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the pending owner can accept ownership.")]
+    fn test_accept_ownership_wrong_account() {
+        let mut amm = AMM::new(owner(), token_a(), token_b(), None);
+        testing_env!(get_owner_ft_transfer_context(owner(), owner(), false));
+        amm.propose_new_owner(alice());
+
+        testing_env!(get_owner_ft_transfer_context(bob(), bob(), false));
+        amm.accept_ownership();
+    }
+
+    #[test]
+    fn test_init_emits_pool_created_event() {
+        testing_env!(VMContextBuilder::new().build());
+        AMM::new(owner(), token_a(), token_b(), None);
+
+        let logs = get_logs();
+        assert_eq!(logs.len(), 1);
+        let event: serde_json::Value =
+            serde_json::from_str(logs[0].strip_prefix("EVENT_JSON:").unwrap()).unwrap();
+        assert_eq!(event["standard"], "amm");
+        assert_eq!(event["version"], "1.0.0");
+        assert_eq!(event["event"], "pool_created");
+        assert_eq!(event["data"][0]["owner"], owner().to_string());
+        assert_eq!(event["data"][0]["token_a"], token_a().to_string());
+        assert_eq!(event["data"][0]["token_b"], token_b().to_string());
+        assert_eq!(event["data"][0]["fee_bps"], DEFAULT_FEE_BPS);
+    }
+
+    #[test]
+    fn test_failed_swap_transfer_logs_reason() {
+        let mut amm = funded_amm();
+        let refund = amm.swap_callback(
+            alice(),
+            0,
+            100_000_000,
+            0,
+            90_909_090_909_090_909,
+            0,
+            Err(PromiseError::Failed),
+        );
+        assert!(matches!(refund, PromiseOrValue::Value(U128(100_000_000))));
+        assert!(get_logs()
+            .iter()
+            .any(|log| log.contains("Recipient not registered with output token")));
+    }
+
+    #[test]
+    fn test_sandwich_emits_warning() {
+        let mut amm = funded_amm();
+        testing_env!(get_owner_ft_transfer_context(owner(), owner(), false));
+        amm.set_sandwich_detection(true);
+
+        let swap = |amm: &mut AMM, sender: AccountId, token: AccountId, amount: u128| {
+            testing_env!(get_owner_ft_transfer_context(sender.clone(), token, false));
+            assert_swapped(amm.ft_on_transfer(
+                sender,
+                near_sdk::json_types::U128(amount),
+                "".to_string(),
+            ));
+            get_logs()
+        };
+
+        // alice front-runs bob and sells back in the same block
+        assert!(swap(&mut amm, alice(), token_a(), 100_000_000).is_empty());
+        assert!(swap(&mut amm, bob(), token_a(), 100_000_000).is_empty());
+        let logs = swap(&mut amm, alice(), token_b(), 90_000_000_000_000_000);
+
+        assert_eq!(logs.len(), 1);
+        let event: serde_json::Value =
+            serde_json::from_str(logs[0].strip_prefix("EVENT_JSON:").unwrap()).unwrap();
+        assert_eq!(event["event"], "possible_sandwich");
+        assert_eq!(event["data"][0]["attacker"], alice().to_string());
+        assert_eq!(event["data"][0]["victim"], bob().to_string());
+    }
+
+    #[test]
+    fn test_init_fits_in_gas_limit() {
+        testing_env!(VMContextBuilder::new()
+            .prepaid_gas(Gas(100 * 10u64.pow(12)))
+            .build());
+        AMM::new(owner(), token_a(), token_b(), None);
+
+        // two ft_metadata calls and their callbacks
+        let attached_gas: u64 = near_sdk::test_utils::get_created_receipts()
+            .iter()
+            .flat_map(|receipt| receipt.actions.iter())
+            .map(|action| match action {
+                near_sdk::mock::VmAction::FunctionCall { gas, .. } => gas.0,
+                _ => 0,
+            })
+            .sum();
+        assert_eq!(attached_gas, 4 * TGAS.0);
+        // used gas includes the gas attached to the promises
+        assert!(env::used_gas() <= env::prepaid_gas());
+    }
+
+    #[test]
+    fn test_market_overview() {
+        let amm = funded_amm();
+        let overview = amm.get_market_overview(near_sdk::json_types::U128(100_000_000));
+
+        assert_eq!(overview.reserve_a.0, 1_000_000_000);
+        assert_eq!(overview.reserve_b.0, 1_000_000_000_000_000_000);
+        assert_eq!(overview.mid_price.0, 10 * PRICE_PRECISION);
+        // 10^18 * 10^8 / (10^9 + 10^8)
+        assert_eq!(overview.amount_out_a_to_b.0, 90_909_090_909_090_909);
+        // 10^9 * 10^8 / (10^18 + 10^8) rounds down to 0
+        assert_eq!(overview.amount_out_b_to_a.0, 0);
+        assert_eq!(overview.fee_bps, 0);
+    }
+
+    fn owner_deposit_both_tokens(amm: &mut AMM) {
+        testing_env!(get_owner_ft_transfer_context(owner(), token_a(), false));
+        amm.ft_on_transfer(
+            owner(),
+            near_sdk::json_types::U128(1_000_000_000),
+            "".to_string(),
+        );
+        testing_env!(get_owner_ft_transfer_context(owner(), token_b(), false));
+        amm.ft_on_transfer(
+            owner(),
+            near_sdk::json_types::U128(1_000_000_000_000_000_000),
+            "".to_string(),
+        );
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let mut amm = funded_amm();
+        owner_deposit_both_tokens(&mut amm);
+        assert_swapped(swap_one_token_a(&mut amm, ""));
+        amm.swap_callback(
+            alice(),
+            0,
+            100_000_000,
+            0,
+            95_238_095_238_095_238,
+            0,
+            Ok(()),
+        );
+
+        let snapshot = amm.snapshot();
+        assert_eq!(snapshot.reserve_a.0, 2_100_000_000);
+        assert_eq!((snapshot.reserve_a, snapshot.reserve_b), amm.get_reserves());
+        assert_eq!(snapshot.ratio, amm.get_ratio());
+        assert_eq!(snapshot.price_a_in_b, amm.get_spot_price(token_a()));
+        assert_eq!(snapshot.price_b_in_a, amm.get_spot_price(token_b()));
+        assert_eq!(snapshot.fee_bps, amm.get_fee_bps());
+        assert_eq!(snapshot.paused, amm.is_paused());
+        assert_eq!(snapshot.swaps_paused, amm.get_swaps_paused());
+        assert_eq!(snapshot.total_shares, amm.get_total_shares());
+    }
+
+    #[test]
+    fn test_deposit_before_metadata() {
+        let mut amm = AMM::new(owner(), token_a(), token_b(), None);
+        owner_deposit_both_tokens(&mut amm);
+        assert!(!amm.is_initialization_complete());
+        assert_eq!(
+            amm.get_balance(token_a()),
+            near_sdk::json_types::U128(1_000_000_000)
+        );
+
+        for (index, metadata) in [(0, token_a_metadata()), (1, token_b_metadata())] {
+            amm.metadata_callback(
+                Ok(FungibleTokenMetadata {
+                    spec: "ft-1.0.0".to_string(),
+                    name: metadata.name,
+                    symbol: metadata.symbol,
+                    icon: None,
+                    reference: None,
+                    reference_hash: None,
+                    decimals: metadata.decimals,
+                }),
+                index,
+            );
+        }
+        assert!(amm.is_initialization_complete());
+        assert_swapped(swap_one_token_a(&mut amm, ""));
+    }
+
+    #[test]
+    #[should_panic(expected = "Pool metadata is not initialized.")]
+    fn test_strict_init_rejects_deposits_before_metadata() {
+        // neither ft_metadata call resolved, so the pool cannot be priced
+        let mut amm = AMM::new(owner(), token_a(), token_b(), None);
+        assert!(!amm.is_initialization_complete());
+        assert!(amm.try_get_metadata(token_a()).is_none());
+
+        testing_env!(get_owner_ft_transfer_context(owner(), owner(), false));
+        amm.set_strict_init(true);
+        owner_deposit_both_tokens(&mut amm);
+    }
+
+    #[test]
+    #[should_panic(expected = "Pool metadata is not initialized.")]
+    fn test_swap_fails_before_metadata() {
+        let mut amm = AMM::new(owner(), token_a(), token_b(), None);
+        owner_deposit_both_tokens(&mut amm);
+        swap_one_token_a(&mut amm, "");
+    }
+
+    #[test]
+    fn test_large_swap_event() {
+        let mut amm = funded_amm();
+        testing_env!(get_owner_ft_transfer_context(owner(), owner(), false));
+        amm.set_large_swap_threshold(Some(near_sdk::json_types::U128(PRICE_PRECISION)));
+
+        // exactly 1 token A is not above the threshold
+        assert_swapped(swap_one_token_a(&mut amm, ""));
+        assert!(get_logs().is_empty());
+
+        testing_env!(get_owner_ft_transfer_context(owner(), owner(), false));
+        amm.set_large_swap_threshold(Some(near_sdk::json_types::U128(PRICE_PRECISION / 2)));
+        assert_swapped(swap_one_token_a(&mut amm, ""));
+
+        let logs = get_logs();
+        assert_eq!(logs.len(), 1);
+        let event: serde_json::Value =
+            serde_json::from_str(logs[0].strip_prefix("EVENT_JSON:").unwrap()).unwrap();
+        assert_eq!(event["event"], "large_swap");
+        assert_eq!(event["data"][0]["sender_id"], alice().to_string());
+        assert_eq!(event["data"][0]["token_in"], token_a().to_string());
+        assert_eq!(event["data"][0]["amount_in"], "100000000");
+        // priced off the reserves left by the first swap
+        assert_eq!(event["data"][0]["amount_out"], "75757575757575757");
+    }
+
+    #[test]
+    fn test_transfers_attach_one_yocto() {
+        let mut amm = funded_amm();
+        assert_eq!(amm.get_transfer_deposit().0, ONE_YOCTO);
+
+        let transfer_deposits = || -> Vec<(String, Balance)> {
+            near_sdk::test_utils::get_created_receipts()
+                .into_iter()
+                .flat_map(|receipt| receipt.actions)
+                .filter_map(|action| match action {
+                    near_sdk::mock::VmAction::FunctionCall {
+                        function_name,
+                        deposit,
+                        ..
+                    } if function_name.starts_with("ft_transfer") => Some((function_name, deposit)),
+                    _ => None,
+                })
+                .collect()
+        };
+
+        swap_one_token_a(&mut amm, "");
+        assert_eq!(
+            transfer_deposits(),
+            vec![("ft_transfer".to_string(), ONE_YOCTO)]
+        );
+
+        swap_one_token_a(&mut amm, r#"{"forward_to": "bob.testnet"}"#);
+        assert_eq!(
+            transfer_deposits(),
+            vec![("ft_transfer_call".to_string(), ONE_YOCTO)]
+        );
+
+        // the unused part of a forwarded output goes back with a plain transfer
+        amm.swap_forward_callback(
+            alice(),
+            bob(),
+            0,
+            100_000_000,
+            0,
+            90_909_090_909_090_909,
+            0,
+            Ok(near_sdk::json_types::U128(0)),
+        );
+        assert_eq!(
+            transfer_deposits(),
+            vec![
+                ("ft_transfer_call".to_string(), ONE_YOCTO),
+                ("ft_transfer".to_string(), ONE_YOCTO)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_human_reserves() {
+        let mut amm = funded_amm();
+        amm.tokens[0].balance = 1_050_000_000;
+        amm.tokens[1].balance = 123;
+
+        let (reserve_a, reserve_b) = amm.get_human_reserves();
+        assert_eq!(reserve_a, "10.50000000");
+        assert_eq!(reserve_b, "0.0000000000000123");
+    }
+
+    #[test]
+    #[should_panic(expected = "Owner deposits must be signed by the owner.")]
+    fn test_spoofed_owner_sender_is_rejected() {
+        let mut amm = funded_amm();
+        testing_env!(get_owner_ft_transfer_context(alice(), token_a(), false));
+        amm.ft_on_transfer(
+            owner(),
+            near_sdk::json_types::U128(100_000_000),
+            "".to_string(),
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Token not supported.")]
+    fn test_owner_sender_from_unknown_token_is_rejected() {
+        let mut amm = funded_amm();
+        testing_env!(get_owner_ft_transfer_context(owner(), bob(), false));
+        amm.ft_on_transfer(
+            owner(),
+            near_sdk::json_types::U128(100_000_000),
+            "".to_string(),
+        );
+    }
+
+    #[test]
+    fn test_spoofed_owner_swap_is_priced() {
+        let mut amm = funded_amm();
+        testing_env!(get_owner_ft_transfer_context(owner(), owner(), false));
+        amm.set_owner_can_swap(true);
+
+        // a swap msg with a spoofed owner sender is a regular swap, the input is not donated
+        testing_env!(get_owner_ft_transfer_context(alice(), token_a(), false));
+        assert_swapped(amm.ft_on_transfer(
+            owner(),
+            near_sdk::json_types::U128(100_000_000),
+            "{}".to_string(),
+        ));
+        assert_eq!(amm.get_balance(token_a()).0, 1_100_000_000);
+        assert_eq!(amm.get_balance(token_b()).0, 909_090_909_090_909_091);
+        assert!(function_call_args("ft_transfer").contains(r#""receiver_id":"owner.testnet""#));
+    }
+
+    #[test]
+    fn test_probe_token_records_compliance() {
+        let mut amm = AMM::new(owner(), token_a(), token_b(), None);
+        assert_eq!(amm.get_token_compliance(token_a()), None);
+
+        testing_env!(get_owner_ft_transfer_context(owner(), owner(), false));
+        amm.probe_token(token_b());
+        assert!(function_call_args("probe_token_callback").contains(r#""index":1"#));
+
+        let metadata = FungibleTokenMetadata {
+            spec: "ft-1.0.0".to_string(),
+            name: "token_a".to_string(),
+            symbol: "TA".to_string(),
+            icon: None,
+            reference: None,
+            reference_hash: None,
+            decimals: 8,
+        };
+        assert!(amm.probe_token_callback(0, Ok(metadata.clone()), Ok(None)));
+        assert!(!amm.probe_token_callback(1, Ok(metadata), Err(PromiseError::Failed)));
+        assert_eq!(amm.get_token_compliance(token_a()), Some(true));
+        assert_eq!(amm.get_token_compliance(token_b()), Some(false));
+    }
+
+    #[test]
+    #[should_panic(expected = "Tokens must be distinct.")]
+    fn test_new_with_identical_tokens() {
+        AMM::new(owner(), token_a(), token_a(), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Token is blocklisted.")]
+    fn test_new_with_blocklisted_token() {
+        AMM::new(
+            owner(),
+            token_a(),
+            "wrap.near".parse().unwrap(),
+            Some(vec!["wrap.near".parse().unwrap()]),
+        );
+    }
+
+    #[test]
+    fn test_new_with_blocklist() {
+        let amm = AMM::new(
+            owner(),
+            token_a(),
+            token_b(),
+            Some(vec!["wrap.near".parse().unwrap()]),
+        );
+        assert_eq!(amm.tokens[1].address, token_b());
+    }
+
+    #[test]
+    fn test_retry_failed_metadata() {
+        let mut amm = AMM::new(owner(), token_a(), token_b(), None);
+        amm.metadata_callback(Err(PromiseError::Failed), 0);
+        assert!(amm.try_get_metadata(token_a()).is_none());
+
+        // anyone can request the metadata again
+        testing_env!(get_owner_ft_transfer_context(alice(), alice(), false));
+        amm.update_metadata(token_a());
+        assert!(function_call_args("metadata_callback").contains(r#""index":0"#));
+
+        amm.metadata_callback(
+            Ok(FungibleTokenMetadata {
+                spec: "ft-1.0.0".to_string(),
+                name: "token_a".to_string(),
+                symbol: "TA".to_string(),
+                icon: None,
+                reference: None,
+                reference_hash: None,
+                decimals: 8,
+            }),
+            0,
+        );
+        assert_eq!(amm.try_get_metadata(token_a()).unwrap().decimals, 8);
+    }
+
+    #[test]
+    fn test_metadata_staleness() {
+        let mut amm = funded_amm();
+        testing_env!(get_owner_ft_transfer_context(owner(), owner(), false));
+        amm.set_metadata_ttl_blocks(Some(100));
+
+        testing_env!(VMContextBuilder::new().block_index(10).build());
+        amm.metadata_callback(
+            Ok(FungibleTokenMetadata {
+                spec: "ft-1.0.0".to_string(),
+                name: "token_a".to_string(),
+                symbol: "TA".to_string(),
+                icon: None,
+                reference: None,
+                reference_hash: None,
+                decimals: 8,
+            }),
+            0,
+        );
+
+        testing_env!(VMContextBuilder::new().block_index(110).build());
+        let status = amm.get_metadata_status(token_a());
+        assert!(status.initialized);
+        assert_eq!(status.last_update_block, 10);
+        assert!(!status.stale);
+
+        testing_env!(VMContextBuilder::new().block_index(111).build());
+        assert!(amm.get_metadata_status(token_a()).stale);
+
+        // token B was never refreshed, so a swap triggers the refresh of both
+        let mut context = get_owner_ft_transfer_context(alice(), token_a(), false);
+        context.block_index = 111;
+        testing_env!(context);
+        amm.ft_on_transfer(
+            alice(),
+            near_sdk::json_types::U128(100_000_000),
+            "".to_string(),
+        );
+        let metadata_requests = near_sdk::test_utils::get_created_receipts()
+            .into_iter()
+            .filter(|receipt| {
+                receipt.actions.iter().any(|action| {
+                    matches!(action, near_sdk::mock::VmAction::FunctionCall { function_name, .. }
+                        if function_name == "ft_metadata")
+                })
+            })
+            .count();
+        assert_eq!(metadata_requests, 2);
+
+        // the refreshes are still in flight, the next swap does not request them again
+        let mut context = get_owner_ft_transfer_context(alice(), token_a(), false);
+        context.block_index = 112;
+        testing_env!(context);
+        amm.ft_on_transfer(
+            alice(),
+            near_sdk::json_types::U128(100_000_000),
+            "".to_string(),
+        );
+        assert!(near_sdk::test_utils::get_created_receipts()
+            .iter()
+            .flat_map(|receipt| receipt.actions.iter())
+            .all(|action| !matches!(action, near_sdk::mock::VmAction::FunctionCall { function_name, .. }
+                if function_name == "ft_metadata")));
+    }
+
+    #[test]
+    fn test_metadata_refresh_keeps_decimals() {
+        let mut amm = funded_amm();
+        amm.metadata_callback(
+            Ok(FungibleTokenMetadata {
+                spec: "ft-1.0.0".to_string(),
+                name: "token_a v2".to_string(),
+                symbol: "TA2".to_string(),
+                icon: None,
+                reference: None,
+                reference_hash: None,
+                decimals: 18,
+            }),
+            0,
+        );
+
+        let metadata = amm.get_metadata(token_a());
+        assert_eq!(metadata.symbol, "TA2");
+        assert_eq!(metadata.decimals, 8);
+    }
+
+    #[test]
+    fn test_owner_deposit_never_transfers() {
+        let mut amm = funded_amm();
+        testing_env!(get_owner_ft_transfer_context(owner(), token_a(), false));
+        let result = amm.ft_on_transfer(
+            owner(),
+            near_sdk::json_types::U128(100_000_000),
+            "".to_string(),
+        );
+
+        assert_refunded(result, 0);
+        assert!(near_sdk::test_utils::get_created_receipts().is_empty());
+        assert_eq!(
+            amm.get_balance(token_a()),
+            near_sdk::json_types::U128(1_100_000_000)
+        );
+    }
+
+    #[test]
+    fn test_geometric_mean_price() {
+        let mut amm = funded_amm();
+        // floor(sqrt(10^27))
+        assert_eq!(amm.get_geometric_mean_price().0, 31_622_776_601_683);
+
+        amm.tokens[0].balance = 4;
+        amm.tokens[1].balance = 9;
+        assert_eq!(amm.get_geometric_mean_price().0, 6);
+    }
+
+    #[test]
+    fn test_min_execution_rate() {
+        let mut amm = funded_amm();
+        testing_env!(get_owner_ft_transfer_context(owner(), owner(), false));
+        // 0.01 token B per token A or 0.01 token A per token B
+        amm.set_min_execution_rate(Some(near_sdk::json_types::U128(PRICE_PRECISION / 100)));
+
+        // 1 token A for ~9.09 token B
+        assert_swapped(swap_one_token_a(&mut amm, ""));
+
+        // only 0.01 token B left, so 1 token A gets ~0.0009 token B
+        amm.tokens[1].balance = 100_000_000_000_000;
+        assert_refunded(swap_one_token_a(&mut amm, ""), 100_000_000);
+    }
+
+    // Independent statement of the swap formula for checking the production math:
+    // in_after_fee = floor(amount_in * (10000 - fee) / 10000)
+    // amount_out = floor(reserve_out * in_after_fee / (reserve_in + in_after_fee))
+    impl AMM {
+        pub fn reference_amount_out(
+            &self,
+            reserve_in: U128,
+            reserve_out: U128,
+            amount_in: U128,
+        ) -> U128 {
+            let fee_numerator = U256::from(10_000u16 - self.fee_bps);
+            let amount_in_after_fee = U256::from(amount_in.0) * fee_numerator / U256::from(10_000);
+            let numerator = U256::from(reserve_out.0) * amount_in_after_fee;
+            let denominator = U256::from(reserve_in.0) + amount_in_after_fee;
+            U128((numerator / denominator).as_u128())
+        }
+    }
+
+    #[test]
+    fn test_quote_matches_reference_amount_out() {
+        let values = [
+            1,
+            1_000,
+            100_000_000,
+            1_000_000_000_000_000_000,
+            10u128.pow(30),
+            u128::MAX / 4,
+            u128::MAX / 2,
+            u128::MAX - 1_000,
+        ];
+        let mut amm = funded_amm();
+
+        for fee_bps in [0, 1, 30, MAX_FEE_BPS] {
+            amm.fee_bps = fee_bps;
+            for reserve_in in values {
+                for reserve_out in values {
+                    for amount_in in values {
+                        // a real reserve and deposit never exceed the token supply
+                        if reserve_in.checked_add(amount_in).is_none() {
+                            continue;
+                        }
+                        amm.tokens[0].balance = reserve_in;
+                        amm.tokens[1].balance = reserve_out;
+                        assert_eq!(
+                            amm.get_quote(token_a(), near_sdk::json_types::U128(amount_in), None),
+                            amm.reference_amount_out(
+                                near_sdk::json_types::U128(reserve_in),
+                                near_sdk::json_types::U128(reserve_out),
+                                near_sdk::json_types::U128(amount_in)
+                            ),
+                            "fee {} reserve_in {} reserve_out {} amount_in {}",
+                            fee_bps,
+                            reserve_in,
+                            reserve_out,
+                            amount_in
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_swap_matches_reference_amount_out() {
+        // 6 and 24 decimals, the swap math works on raw amounts regardless
+        let cases = [
+            (1_000_000, 10u128.pow(24), 1_000, 30),
+            (10u128.pow(12), 10u128.pow(30), 123_456_789, 30),
+            (10u128.pow(6), 10u128.pow(36), 10u128.pow(6), 0),
+            (u128::MAX / 2, u128::MAX / 2, u128::MAX / 4, 1000),
+        ];
+        for (reserve_in, reserve_out, amount_in, fee_bps) in cases {
+            let mut amm = AMM::new(owner(), token_a(), token_b(), None);
+            amm.fee_bps = fee_bps;
+            amm.tokens[0].metadata = Some(TokenMetadata::new("A".to_string(), "A".to_string(), 6));
+            amm.tokens[1].metadata = Some(TokenMetadata::new("B".to_string(), "B".to_string(), 24));
+            amm.tokens[0].balance = reserve_in;
+            amm.tokens[1].balance = reserve_out;
+
+            testing_env!(get_owner_ft_transfer_context(alice(), token_a(), false));
+            assert_swapped(amm.ft_on_transfer(
+                alice(),
+                near_sdk::json_types::U128(amount_in),
+                "".to_string(),
+            ));
+            let expected = amm.reference_amount_out(
+                near_sdk::json_types::U128(reserve_in),
+                near_sdk::json_types::U128(reserve_out),
+                near_sdk::json_types::U128(amount_in),
+            );
+            assert!(function_call_args("ft_transfer")
+                .contains(&format!(r#""amount":"{}""#, expected.0)));
+        }
+    }
+
+    fn function_call_args(function: &str) -> String {
+        near_sdk::test_utils::get_created_receipts()
+            .into_iter()
+            .flat_map(|receipt| receipt.actions)
+            .find_map(|action| match action {
+                near_sdk::mock::VmAction::FunctionCall {
+                    function_name,
+                    args,
+                    ..
+                } if function_name == function => Some(String::from_utf8(args).unwrap()),
+                _ => None,
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn test_swap_fee() {
+        let mut amm = funded_amm();
+        testing_env!(get_owner_ft_transfer_context(owner(), owner(), false));
+        amm.set_fee_bps(30);
+        assert_eq!(amm.get_fee_bps(), 30);
+
+        assert_swapped(swap_one_token_a(&mut amm, ""));
+
+        // same as the fee-free output for 10^8 * 0.997 token A
+        let amount_out =
+            AMM::compute_amount_out(1_000_000_000, 1_000_000_000_000_000_000, 99_700_000);
+        assert_eq!(amount_out, 90_661_089_388_014_913);
+        assert!(function_call_args("ft_transfer").contains(r#""amount":"90661089388014913""#));
+
+        // the whole input, fee included, is added to the reserve
+        assert!(function_call_args("swap_callback").contains(r#""amount":100000000"#));
+        amm.swap_callback(
+            alice(),
+            0,
+            100_000_000,
+            0,
+            90_661_089_388_014_913,
+            0,
+            Ok(()),
+        );
+        assert_eq!(amm.get_balance(token_a()).0, 1_100_000_000);
+        assert_eq!(amm.get_balance(token_b()).0, 909_338_910_611_985_087);
+        assert!(
+            U256::from(1_100_000_000u128) * U256::from(909_338_910_611_985_087u128)
+                > U256::from(1_000_000_000u128) * U256::from(1_000_000_000_000_000_000u128)
+        );
+    }
+
+    #[test]
+    fn test_fee_history() {
+        let mut amm = AMM::new(owner(), token_a(), token_b(), None);
+        let mut context = VMContextBuilder::new();
+        context.predecessor_account_id(owner());
+        for (timestamp, fee_bps) in [(1, 10), (2, 50), (3, 5)] {
+            testing_env!(context.block_timestamp(timestamp).build());
+            amm.set_fee_bps(fee_bps);
+        }
+
+        assert_eq!(amm.get_fee_history(), vec![(1, 10), (2, 50), (3, 5)]);
+        let event = &get_events()[0];
+        assert_eq!(event["event"], "fee_changed");
+        assert_eq!(event["data"][0]["old_fee_bps"], 50);
+        assert_eq!(event["data"][0]["new_fee_bps"], 5);
+        assert_eq!(event["data"][0]["timestamp"], 3);
+
+        // only the latest changes are kept
+        for _ in 0..FEE_HISTORY_SIZE {
+            amm.set_fee_bps(30);
+        }
+        assert_eq!(amm.get_fee_history().len(), FEE_HISTORY_SIZE);
+        assert_eq!(amm.get_fee_history()[0], (3, 30));
+    }
+
+    #[test]
+    fn test_recent_events() {
+        let mut amm = AMM::new(owner(), token_a(), token_b(), None);
+        assert_eq!(amm.get_recent_events(10)[0]["event"], "pool_created");
+
+        testing_env!(get_owner_ft_transfer_context(owner(), owner(), false));
+        for fee_bps in 1..=RECENT_EVENTS_SIZE as u16 {
+            amm.set_fee_bps(fee_bps);
+        }
+
+        // the pool_created event was evicted by the last fee change
+        let events = amm.get_recent_events(100);
+        assert_eq!(events.len(), RECENT_EVENTS_SIZE as usize);
+        assert_eq!(events[0]["event"], "fee_changed");
+        assert_eq!(events[0]["data"][0]["new_fee_bps"], 1);
+        assert_eq!(events[49]["data"][0]["new_fee_bps"], 50);
+
+        let latest = amm.get_recent_events(2);
+        assert_eq!(latest[0]["data"][0]["new_fee_bps"], 49);
+        assert_eq!(latest[1]["data"][0]["new_fee_bps"], 50);
+    }
+
+    #[test]
+    fn test_protocol_fees_accrue_on_swaps() {
+        let mut amm = funded_amm();
+        testing_env!(get_owner_ft_transfer_context(owner(), owner(), false));
+        amm.set_fee_bps(30);
+        amm.set_protocol_fee_bps(5_000);
+
+        // half of the 300000 fee on 10^8 token A goes to the protocol
+        assert_swapped(swap_one_token_a(&mut amm, ""));
+        let callback_args = function_call_args("swap_callback");
+        assert!(callback_args.contains(r#""protocol_fee":150000"#));
+
+        amm.swap_callback(
+            alice(),
+            0,
+            100_000_000,
+            0,
+            90_661_089_388_014_913,
+            150_000,
+            Ok(()),
+        );
+        assert_eq!(amm.get_protocol_fees(token_a()).0, 150_000);
+        assert_eq!(amm.get_protocol_fees(token_b()).0, 0);
+        assert_eq!(amm.get_balance(token_a()).0, 1_099_850_000);
+    }
+
+    #[test]
+    fn test_get_amount_in_inverts_get_quote() {
+        let mut amm = funded_amm();
+        testing_env!(get_owner_ft_transfer_context(owner(), owner(), false));
+        amm.set_fee_bps(30);
+
+        for (token_in, token_out, amount_in) in [
+            (token_a(), token_b(), 100_000_000),
+            (token_a(), token_b(), 12_345),
+            (token_b(), token_a(), 50_000_000_000_000_000),
+            (token_b(), token_a(), 777_777_777_777),
+        ] {
+            let amount_out = amm.get_quote(token_in.clone(), U128(amount_in), None);
+            let required_in = amm.get_amount_in(token_out, amount_out, None).0;
+
+            // never more than the original input, and enough to get the same output
+            assert!(required_in <= amount_in);
+            assert!(amm.get_quote(token_in, U128(required_in), None).0 >= amount_out.0);
+            assert!((amount_in - required_in) * 1_000 <= amount_in);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Not enough funds to complete the trade.")]
+    fn test_get_amount_in_whole_reserve() {
+        let amm = funded_amm();
+        amm.get_amount_in(token_a(), U128(1_000_000_000), None);
+    }
+
+    #[test]
+    fn test_compute_rebalance_swap() {
+        // 1 raw TA is worth 10^9 raw TB
+        let amm = funded_amm();
+
+        // 3 TA against 1 TA worth of TB: swap 1 TA
+        let (token, amount) =
+            amm.compute_rebalance_swap(U128(300_000_000), U128(100_000_000_000_000_000));
+        assert_eq!((token, amount.0), (token_a(), 100_000_000));
+
+        // 1 raw TA against 10^9 + 7 raw TB of value, down to rounding
+        let (token, amount) = amm.compute_rebalance_swap(U128(1), U128(3_000_000_007));
+        assert_eq!((token, amount.0), (token_b(), 1_000_000_003));
+        let value_a = 1_000_000_000 + amount.0;
+        let value_b = 3_000_000_007 - amount.0;
+        assert!(value_a.abs_diff(value_b) <= 1);
+
+        let (token, amount) = amm.compute_rebalance_swap(U128(0), U128(0));
+        assert_eq!((token, amount.0), (token_a(), 0));
+    }
+
+    #[test]
+    #[should_panic(expected = "Cannot compute the rebalance for an empty pool.")]
+    fn test_compute_rebalance_swap_empty_pool() {
+        let amm = AMM::new(owner(), token_a(), token_b(), None);
+        amm.compute_rebalance_swap(U128(100), U128(100));
+    }
+
+    #[test]
+    fn test_quote_custom() {
+        let amm = AMM::new(owner(), token_a(), token_b(), None);
+        let quote = |reserve_in: u128, reserve_out: u128, amount_in: u128, fee_bps: u16| {
+            amm.quote_custom(
+                U128(reserve_in),
+                U128(reserve_out),
+                U128(amount_in),
+                fee_bps,
+            )
+            .0
+        };
+
+        // independent of the (empty) pool
+        assert_eq!(quote(1_000, 1_000, 100, 0), 90);
+        assert_eq!(quote(1_000, 1_000, 100, 30), 90);
+        assert_eq!(quote(1_000, 1_000, 100, MAX_FEE_BPS), 82);
+        assert_eq!(quote(1_000, 1_000, 100, 10_000), 0);
+        assert_eq!(
+            quote(1_000_000_000, 1_000_000_000_000_000_000, 100_000_000, 0),
+            90_909_090_909_090_909
+        );
+        assert_eq!(
+            quote(1_000_000_000, 1_000_000_000_000_000_000, 100_000_000, 30),
+            90_661_089_388_014_913
+        );
+        assert_eq!(quote(1_000, 1_000, 0, 30), 0);
+        assert_eq!(quote(0, 1_000, 100, 0), 1_000);
+        assert_eq!(quote(0, 0, 0, 0), 0);
+        // the input is 0 after the fee
+        assert_eq!(quote(0, 1_000, 1, 30), 0);
+        assert_eq!(quote(0, 1_000, 100, 10_000), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Fee cannot exceed 10000 bps.")]
+    fn test_quote_custom_fee_too_high() {
+        let amm = AMM::new(owner(), token_a(), token_b(), None);
+        amm.quote_custom(U128(1_000), U128(1_000), U128(100), 10_001);
+    }
+
+    #[test]
+    fn test_cumulative_prices() {
+        let mut amm = funded_amm();
+        assert_eq!(amm.get_cumulative_prices(), (U128(0), U128(0), 0));
+
+        // 10 seconds at 10^9 raw token B per token A
+        testing_env!(VMContextBuilder::new()
+            .block_timestamp(10_000_000_000)
+            .build());
+        amm.apply_swap(0, 100_000_000, 90_909_090_909_090_909, 0);
+        let (price_0, price_1, timestamp) = amm.get_cumulative_prices();
+        assert_eq!(price_0.0, 10 * 1_000_000_000 * PRICE_PRECISION);
+        assert_eq!(price_1.0, 10_000_000_000);
+        assert_eq!(timestamp, 10);
+
+        // the accumulators keep growing across swaps in both directions
+        testing_env!(VMContextBuilder::new()
+            .block_timestamp(20_000_000_000)
+            .build());
+        amm.apply_swap(1, 90_909_090_909_090_909, 99_999_999, 0);
+        let (next_price_0, next_price_1, next_timestamp) = amm.get_cumulative_prices();
+        assert!(next_price_0.0 > price_0.0);
+        assert!(next_price_1.0 > price_1.0);
+        assert_eq!(next_timestamp, 20);
+
+        // a swap in the same second adds nothing
+        amm.apply_swap(0, 0, 0, 0);
+        assert_eq!(
+            amm.get_cumulative_prices(),
+            (next_price_0, next_price_1, next_timestamp)
+        );
+    }
+
+    #[test]
+    fn test_last_swap_reserves() {
+        let mut amm = funded_amm();
+        assert_eq!(amm.get_last_swap_reserves(), None);
+
+        assert_swapped(swap_one_token_a(&mut amm, ""));
+        amm.swap_callback(
+            alice(),
+            0,
+            100_000_000,
+            0,
+            90_909_090_909_090_909,
+            0,
+            Ok(()),
+        );
+        assert_eq!(
+            amm.get_last_swap_reserves(),
+            Some((
+                U128(1_000_000_000),
+                U128(1_000_000_000_000_000_000),
+                U128(1_100_000_000),
+                U128(909_090_909_090_909_091)
+            ))
+        );
+    }
+
+    #[test]
+    fn test_swap_preserves_invariant() {
+        let mut amm = funded_amm();
+        testing_env!(get_owner_ft_transfer_context(owner(), owner(), false));
+        amm.set_fee_bps(30);
+        let k_before = U256::from(1_000_000_000u128) * U256::from(1_000_000_000_000_000_000u128);
+
+        amm.swap_callback(
+            alice(),
+            0,
+            100_000_000,
+            0,
+            90_661_089_388_014_913,
+            0,
+            Ok(()),
+        );
+        let k_after =
+            U256::from(amm.get_balance(token_a()).0) * U256::from(amm.get_balance(token_b()).0);
+        assert!(k_after >= k_before);
+    }
+
+    #[test]
+    fn test_concurrent_swaps_price_off_moved_reserves() {
+        let mut amm = funded_amm();
+        // the second swap is priced off the reserves left by the first, before either callback ran
+        assert_swapped(swap_one_token_a(&mut amm, ""));
+        assert_eq!(amm.get_balance(token_b()).0, 909_090_909_090_909_091);
+        assert_swapped(swap_one_token_a(&mut amm, ""));
+        assert_eq!(amm.get_balance(token_a()).0, 1_200_000_000);
+        assert_eq!(amm.get_balance(token_b()).0, 833_333_333_333_333_334);
+
+        for amount_out in [90_909_090_909_090_909, 75_757_575_757_575_757] {
+            amm.swap_callback(alice(), 0, 100_000_000, 0, amount_out, 0, Ok(()));
+        }
+        assert_eq!(amm.get_balance(token_a()).0, 1_200_000_000);
+        assert_eq!(amm.get_balance(token_b()).0, 833_333_333_333_333_334);
+        assert!(!amm.get_swaps_paused());
+    }
+
+    #[test]
+    fn test_pause_does_not_block_pending_swap() {
+        let mut amm = funded_amm();
+        assert_swapped(swap_one_token_a(&mut amm, ""));
+
+        testing_env!(get_owner_ft_transfer_context(owner(), owner(), false));
+        amm.set_paused(true);
+        amm.swaps_paused = true;
+
+        let refund = amm.swap_callback(
+            alice(),
+            0,
+            100_000_000,
+            0,
+            90_909_090_909_090_909,
+            0,
+            Ok(()),
+        );
+        assert!(matches!(refund, PromiseOrValue::Value(U128(0))));
+        assert_eq!(amm.get_balance(token_a()).0, 1_100_000_000);
+        assert_eq!(amm.get_balance(token_b()).0, 909_090_909_090_909_091);
+    }
+
+    #[test]
+    #[should_panic(expected = "Pool not initialized.")]
+    fn test_swap_into_empty_reserve() {
+        let mut amm = funded_amm();
+        amm.tokens[1].balance = 0;
+        swap_one_token_a(&mut amm, "");
+    }
+
+    #[test]
+    fn test_volume_sums_normalized_inputs() {
+        let mut amm = funded_amm();
+        // 1 TA (8 decimals), then 5 TB (16 decimals)
+        amm.commit_swap(&alice(), 0, 100_000_000, 90_909_090_909_090_909);
+        amm.commit_swap(&alice(), 1, 50_000_000_000_000_000, 53_080_569);
+        assert_eq!(amm.get_volume(alice()).0, 6 * PRICE_PRECISION);
+        assert_eq!(amm.get_volume(bob()).0, 0);
+    }
+
+    #[test]
+    fn test_volume_discount_lowers_the_fee() {
+        let mut amm = funded_amm();
+        testing_env!(get_owner_ft_transfer_context(owner(), owner(), false));
+        amm.set_fee_bps(30);
+        amm.set_fee_discount_tiers(vec![(U128(PRICE_PRECISION), 5_000)]);
+
+        // one swap of 1 TA reaches the tier
+        assert_swapped(swap_one_token_a(&mut amm, ""));
+        amm.commit_swap(&alice(), 0, 100_000_000, 0);
+        assert_eq!(amm.get_volume(alice()).0, PRICE_PRECISION);
+        assert_eq!(amm.get_account_fee_bps(alice()), 15);
+        assert_eq!(amm.get_account_fee_bps(bob()), 30);
+
+        let token_out_amount = |args: String| {
+            let args: serde_json::Value = serde_json::from_str(&args).unwrap();
+            args["token_out_amount"].as_u64().unwrap()
+        };
+        // the quote for alice uses her fee, and the swap pays exactly that with no slippage
+        let alice_quote = amm.get_quote(token_a(), U128(100_000_000), Some(alice()));
+        assert!(alice_quote.0 > amm.get_quote(token_a(), U128(100_000_000), None).0);
+        assert_eq!(
+            amm.get_amount_in(token_b(), alice_quote, Some(alice())).0,
+            100_000_000
+        );
+        assert_swapped(swap_one_token_a(&mut amm, r#"{"max_slippage_bps": 0}"#));
+        let alice_amount_out = token_out_amount(function_call_args("swap_callback"));
+        assert_eq!(u128::from(alice_amount_out), alice_quote.0);
+
+        testing_env!(get_owner_ft_transfer_context(bob(), token_a(), false));
+        assert_swapped(amm.ft_on_transfer(bob(), U128(100_000_000), "".to_string()));
+        let bob_amount_out = token_out_amount(function_call_args("swap_callback"));
+        assert!(alice_amount_out > bob_amount_out);
+    }
+
+    #[test]
+    fn test_withdraw_protocol_fees() {
+        let mut amm = funded_amm();
+        amm.protocol_fees[0] = 150_000;
+        testing_env!(get_owner_ft_transfer_context(owner(), owner(), false));
+        amm.withdraw_protocol_fees(token_a());
+        assert_eq!(amm.get_protocol_fees(token_a()).0, 0);
+
+        let transfer_args = function_call_args("ft_transfer");
+        assert!(transfer_args.contains(r#""receiver_id":"owner.testnet","amount":"150000""#));
+
+        // a failed transfer restores the accrued fees
+        amm.withdraw_protocol_fees_callback(0, 150_000, Err(PromiseError::Failed));
+        assert_eq!(amm.get_protocol_fees(token_a()).0, 150_000);
+        assert_eq!(amm.get_balance(token_a()).0, 1_000_000_000);
+    }
+
+    #[test]
+    #[should_panic(expected = "No protocol fees to withdraw.")]
+    fn test_withdraw_without_protocol_fees() {
+        let mut amm = funded_amm();
+        testing_env!(get_owner_ft_transfer_context(owner(), owner(), false));
+        amm.withdraw_protocol_fees(token_b());
+    }
+
+    #[test]
+    fn test_get_quote_matches_swap() {
+        let mut amm = funded_amm();
+        testing_env!(get_owner_ft_transfer_context(owner(), owner(), false));
+        amm.set_fee_bps(30);
+
+        let quote = amm.get_quote(token_a(), near_sdk::json_types::U128(100_000_000), None);
+        assert_eq!(quote.0, 90_661_089_388_014_913);
+
+        assert_swapped(swap_one_token_a(&mut amm, ""));
+        assert!(function_call_args("ft_transfer").contains(&format!(r#""amount":"{}""#, quote.0)));
+    }
+
+    #[test]
+    fn test_quote_without_metadata() {
+        let mut amm = AMM::new(owner(), token_a(), token_b(), None);
+        amm.tokens[0].balance = 1_000_000_000;
+        amm.tokens[1].balance = 1_000_000_000_000_000_000;
+
+        // quotes only need the reserves, not the metadata
+        let quote = amm.get_quote(token_a(), U128(100_000_000), None);
+        assert_eq!(quote.0, 90_661_089_388_014_913);
+        assert_eq!(amm.get_amount_in(token_b(), quote, None).0, 100_000_000);
+    }
+
+    #[test]
+    #[should_panic(expected = "Pool metadata is not initialized.")]
+    fn test_spot_price_without_metadata() {
+        let mut amm = AMM::new(owner(), token_a(), token_b(), None);
+        amm.tokens[0].balance = 1_000_000_000;
+        amm.tokens[1].balance = 1_000_000_000_000_000_000;
+        amm.get_spot_price(token_a());
+    }
+
+    #[test]
+    fn test_get_quote_for_empty_pool() {
+        let amm = AMM::new(owner(), token_a(), token_b(), None);
+        assert_eq!(
+            amm.get_quote(token_b(), near_sdk::json_types::U128(100_000_000), None)
+                .0,
+            0
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Token not supported.")]
+    fn test_get_balance_for_foreign_token() {
+        let amm = funded_amm();
+        amm.get_balance("token_c.testnet".parse().unwrap());
+    }
+
+    #[test]
+    #[should_panic(expected = "Token not supported.")]
+    fn test_get_quote_for_foreign_token() {
+        let amm = funded_amm();
+        amm.get_quote(alice(), near_sdk::json_types::U128(100_000_000), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Fee cannot exceed 1000 bps.")]
+    fn test_swap_fee_is_capped() {
+        let mut amm = funded_amm();
+        testing_env!(get_owner_ft_transfer_context(owner(), owner(), false));
+        amm.set_fee_bps(1001);
+    }
+
+    #[test]
+    fn test_default_swap_fee() {
+        let amm = AMM::new(owner(), token_a(), token_b(), None);
+        assert_eq!(amm.get_fee_bps(), DEFAULT_FEE_BPS);
+    }
+
+    #[test]
+    fn test_quote_reference() {
+        let amm = funded_amm();
+        // 1 token A (10^8) is worth 10 token B (10^17)
+        let (amount_a, amount_b) =
+            amm.quote_reference(token_a(), near_sdk::json_types::U128(100_000_000));
+        assert_eq!(amount_a.0, 100_000_000);
+        assert_eq!(amount_b.0, 100_000_000_000_000_000);
+
+        let (amount_a, amount_b) = amm.quote_reference(
+            token_b(),
+            near_sdk::json_types::U128(50_000_000_000_000_000),
+        );
+        assert_eq!(amount_a.0, 50_000_000);
+        assert_eq!(amount_b.0, 50_000_000_000_000_000);
+    }
+
+    fn get_events() -> Vec<serde_json::Value> {
+        get_logs()
+            .iter()
+            .filter_map(|log| log.strip_prefix("EVENT_JSON:"))
+            .map(|event| serde_json::from_str(event).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_swap_event() {
+        let mut amm = funded_amm();
+        testing_env!(VMContextBuilder::new().build());
+        amm.swap_callback(
+            alice(),
+            0,
+            100_000_000,
+            0,
+            90_909_090_909_090_909,
+            0,
+            Ok(()),
+        );
+
+        let events = get_events();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0]["event"], "swap");
+        assert_eq!(events[0]["data"][0]["sender_id"], alice().to_string());
+        assert_eq!(events[0]["data"][0]["token_in"], token_a().to_string());
+        assert_eq!(events[0]["data"][0]["token_out"], token_b().to_string());
+        assert_eq!(events[0]["data"][0]["amount_in"], "100000000");
+        assert_eq!(events[0]["data"][0]["amount_out"], "90909090909090909");
+
+        // a failed transfer is not a swap
+        testing_env!(VMContextBuilder::new().build());
+        amm.swap_callback(
+            alice(),
+            0,
+            100_000_000,
+            0,
+            90_909_090_909_090_909,
+            0,
+            Err(PromiseError::Failed),
+        );
+        assert!(get_events().is_empty());
+    }
+
+    #[test]
+    fn test_deposit_event() {
+        let mut amm = funded_amm();
+        testing_env!(get_owner_ft_transfer_context(owner(), token_b(), false));
+        amm.ft_on_transfer(owner(), near_sdk::json_types::U128(5_000), "".to_string());
+
+        let events = get_events();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0]["event"], "deposit");
+        assert_eq!(events[0]["data"][0]["token"], token_b().to_string());
+        assert_eq!(events[0]["data"][0]["amount"], "5000");
+    }
+
+    #[test]
+    fn test_export_state() {
+        let mut amm = funded_amm();
+        testing_env!(get_owner_ft_transfer_context(owner(), owner(), false));
+        amm.set_fee_bps(25);
+        amm.set_max_drift_bps(Some(50));
+        amm.set_max_single_deposit(token_b(), Some(near_sdk::json_types::U128(1_000)));
+        amm.apply_swap(0, 100_000_000, 90_909_090_909_090_909, 0);
+        amm.swap_callback(
+            alice(),
+            0,
+            100_000_000,
+            0,
+            90_909_090_909_090_909,
+            0,
+            Ok(()),
+        );
+
+        let state = amm.export_state();
+        assert_eq!(state.owner, owner());
+        assert_eq!(state.tokens.len(), 2);
+        assert_eq!(state.tokens[0].address, token_a());
+        assert_eq!(state.tokens[0].reserve.0, 1_100_000_000);
+        assert_eq!(state.tokens[0].metadata.as_ref().unwrap().symbol, "TA");
+        assert_eq!(state.tokens[1].reserve.0, 909_090_909_090_909_091);
+        assert_eq!(state.tokens[1].max_single_deposit.unwrap().0, 1_000);
+        assert_eq!(state.fee_bps, 25);
+        assert_eq!(state.max_drift_bps, Some(50));
+        assert!(state.has_swapped);
+        assert!(!state.swaps_paused);
+    }
+
+    fn add_liquidity(amm: &mut AMM, account_id: AccountId, token: AccountId, amount: u128) {
+        add_liquidity_with_refund(amm, account_id, token, amount, 0);
+    }
+
+    fn add_liquidity_with_refund(
+        amm: &mut AMM,
+        account_id: AccountId,
+        token: AccountId,
+        amount: u128,
+        refund: u128,
+    ) {
+        testing_env!(get_owner_ft_transfer_context(
+            account_id.clone(),
+            token,
+            false
+        ));
+        assert_refunded(
+            amm.ft_on_transfer(
+                account_id,
+                near_sdk::json_types::U128(amount),
+                r#"{"action": "add_liquidity"}"#.to_string(),
+            ),
+            refund,
+        );
+    }
+
+    #[test]
+    fn test_first_liquidity_deposit() {
+        let mut amm = AMM::new(owner(), token_a(), token_b(), None);
+
+        add_liquidity(&mut amm, alice(), token_a(), 400_000_000);
+        assert_eq!(amm.get_pending_deposit(alice()).0 .0, 400_000_000);
+        assert_eq!(amm.get_total_shares().0, 0);
+
+        add_liquidity(&mut amm, alice(), token_b(), 900_000_000_000_000_000);
+        // sqrt(4 * 10^8 * 9 * 10^17), minus the locked shares
+        assert_eq!(
+            amm.get_shares(alice()).0,
+            18_973_665_961_010 - MINIMUM_LIQUIDITY
+        );
+        assert_eq!(amm.get_total_shares().0, 18_973_665_961_010);
+        assert_eq!(amm.get_pending_deposit(alice()).0 .0, 0);
+        assert_eq!(amm.get_balance(token_a()).0, 400_000_000);
+        assert_eq!(amm.get_balance(token_b()).0, 900_000_000_000_000_000);
+    }
+
+    #[test]
+    fn test_proportional_liquidity_deposit() {
+        let mut amm = funded_amm();
+
+        add_liquidity(&mut amm, bob(), token_b(), 100_000_000_000_000_000);
+        // only the token A matching the pool ratio is used, the excess is refunded
+        add_liquidity_with_refund(&mut amm, bob(), token_a(), 200_000_000, 100_000_000);
+
+        // the owner's seed is credited as sqrt(10^9 * 10^18) shares, minus the locked ones
+        let seeded_shares = 31_622_776_601_683;
+        assert_eq!(amm.get_shares(owner()).0, seeded_shares - MINIMUM_LIQUIDITY);
+        // 10% of the reserve B is the smaller share of the two sides
+        assert_eq!(amm.get_shares(bob()).0, seeded_shares / 10);
+        assert_eq!(amm.get_total_shares().0, seeded_shares + seeded_shares / 10);
+        assert_eq!(amm.get_balance(token_a()).0, 1_100_000_000);
+    }
+
+    #[test]
+    fn test_excess_of_held_side_stays_pending() {
+        let mut amm = funded_amm();
+
+        add_liquidity(&mut amm, bob(), token_a(), 200_000_000);
+        add_liquidity(&mut amm, bob(), token_b(), 100_000_000_000_000_000);
+        assert_eq!(amm.get_balance(token_a()).0, 1_100_000_000);
+        assert_eq!(amm.get_pending_deposit(bob()).0 .0, 100_000_000);
+        assert_eq!(amm.pending_totals, [100_000_000, 0]);
+    }
+
+    #[test]
+    fn test_withdraw_pending() {
+        let mut amm = funded_amm();
+        add_liquidity(&mut amm, bob(), token_a(), 100_000_000);
+
+        testing_env!(get_owner_ft_transfer_context(bob(), bob(), false));
+        amm.withdraw_pending();
+        assert_eq!(amm.get_pending_deposit(bob()).0 .0, 0);
+        assert_eq!(amm.pending_totals, [0, 0]);
+        assert!(function_call_args("ft_transfer").contains(r#""amount":"100000000""#));
+
+        // a failed transfer keeps the deposit pending
+        amm.withdraw_pending_callback(bob(), 0, 100_000_000, Err(PromiseError::Failed));
+        assert_eq!(amm.get_pending_deposit(bob()).0 .0, 100_000_000);
+        assert_eq!(amm.pending_totals, [100_000_000, 0]);
+    }
+
+    #[test]
+    #[should_panic(expected = "No pending deposit to withdraw.")]
+    fn test_withdraw_pending_without_deposit() {
+        let mut amm = funded_amm();
+        testing_env!(get_owner_ft_transfer_context(bob(), bob(), false));
+        amm.withdraw_pending();
+    }
+
+    #[test]
+    #[should_panic(expected = "Seed amounts exceed the untracked token balances.")]
+    fn test_record_seed_excludes_pending_deposits() {
+        let mut amm = AMM::new(owner(), token_a(), token_b(), None);
+        add_liquidity(&mut amm, bob(), token_a(), 100_000_000);
+
+        // the AMM only holds bob's pending deposit, which cannot be claimed as a seed
+        amm.record_seed_callback(
+            near_sdk::json_types::U128(100_000_000),
+            near_sdk::json_types::U128(0),
+            Ok(near_sdk::json_types::U128(100_000_000)),
+            Ok(near_sdk::json_types::U128(0)),
+        );
+    }
+
+    #[test]
+    fn test_owner_deposit_mints_shares_once_lps_joined() {
+        let mut amm = funded_amm();
+        add_liquidity(&mut amm, bob(), token_a(), 100_000_000);
+        add_liquidity(&mut amm, bob(), token_b(), 100_000_000_000_000_000);
+
+        // a one-sided owner deposit is held until the other side arrives
+        add_liquidity(&mut amm, owner(), token_a(), 100_000_000);
+        assert_eq!(amm.get_balance(token_a()).0, 1_100_000_000);
+        assert_eq!(amm.get_pending_deposit(owner()).0 .0, 100_000_000);
+    }
+
+    #[test]
+    fn test_simulate_reserves_after_add() {
+        let mut amm = funded_amm();
+
+        // off-ratio amounts, the token B excess is refunded
+        let projected = amm.simulate_reserves_after_add(
+            near_sdk::json_types::U128(100_000_000),
+            near_sdk::json_types::U128(300_000_000_000_000_000),
+        );
+        add_liquidity(&mut amm, bob(), token_a(), 100_000_000);
+        add_liquidity_with_refund(
+            &mut amm,
+            bob(),
+            token_b(),
+            300_000_000_000_000_000,
+            200_000_000_000_000_000,
+        );
+
+        assert_eq!(
+            projected,
+            (amm.get_balance(token_a()), amm.get_balance(token_b()))
+        );
+        assert_eq!(projected.1 .0, 1_100_000_000_000_000_000);
+    }
+
+    #[test]
+    fn test_remove_all_liquidity() {
+        let mut amm = AMM::new(owner(), token_a(), token_b(), None);
+        add_liquidity(&mut amm, alice(), token_a(), 400_000_000);
+        add_liquidity(&mut amm, alice(), token_b(), 900_000_000_000_000_000);
+
+        testing_env!(get_owner_ft_transfer_context(alice(), alice(), false));
+        amm.remove_liquidity(amm.get_shares(alice()));
+        assert_eq!(amm.get_shares(alice()).0, 0);
+        // the locked shares keep their part of the reserves
+        assert_eq!(amm.get_total_shares().0, MINIMUM_LIQUIDITY);
+        assert_eq!(amm.get_balance(token_a()).0, 1);
+        assert_eq!(amm.get_balance(token_b()).0, 47_434_165);
+
+        let receipts = near_sdk::test_utils::get_created_receipts();
+        let transfer_args = |token: AccountId| {
+            receipts
+                .iter()
+                .filter(|receipt| receipt.receiver_id == token)
+                .flat_map(|receipt| receipt.actions.iter())
+                .find_map(|action| match action {
+                    near_sdk::mock::VmAction::FunctionCall {
+                        function_name,
+                        args,
+                        ..
+                    } if function_name == "ft_transfer" => {
+                        Some(String::from_utf8(args.clone()).unwrap())
+                    }
+                    _ => None,
+                })
+                .unwrap()
+        };
+        assert!(transfer_args(token_a()).contains(r#""amount":"399999999""#));
+        assert!(transfer_args(token_b()).contains(r#""amount":"899999999952565835""#));
+    }
+
+    #[test]
+    fn test_remove_proportional_liquidity() {
+        let mut amm = funded_amm();
+        add_liquidity(&mut amm, bob(), token_a(), 100_000_000);
+        add_liquidity(&mut amm, bob(), token_b(), 100_000_000_000_000_000);
+
+        // bob owns 1/11 of the pool after matching 10% of the owner's reserves
+        testing_env!(get_owner_ft_transfer_context(bob(), bob(), false));
+        amm.remove_liquidity(amm.get_shares(bob()));
+        // the rounding dust stays in the pool
+        assert_eq!(amm.get_balance(token_a()).0, 1_000_000_001);
+        assert_eq!(amm.get_balance(token_b()).0, 1_000_000_000_000_008_625);
+        assert_eq!(
+            amm.get_total_shares().0,
+            amm.get_shares(owner()).0 + MINIMUM_LIQUIDITY
+        );
+
+        amm.remove_liquidity_callback(
+            bob(),
+            near_sdk::json_types::U128(3_162_277_660_168),
+            99_999_999,
+            99_999_999_999_991_375,
+            Ok(()),
+            Ok(()),
+        );
+        assert_eq!(get_events()[0]["event"], "remove_liquidity");
+    }
+
+    #[test]
+    fn test_failed_remove_liquidity_callback() {
+        let mut amm = funded_amm();
+        amm.shares.insert(&bob(), &0);
+
+        // both transfers failed, the reserves and the shares are restored
+        amm.remove_liquidity_callback(
+            bob(),
+            near_sdk::json_types::U128(100),
+            100_000_000,
+            100_000_000_000_000_000,
+            Err(PromiseError::Failed),
+            Err(PromiseError::Failed),
+        );
+        assert_eq!(amm.get_shares(bob()).0, 100);
+        assert_eq!(amm.get_total_shares().0, 100);
+        assert_eq!(amm.get_balance(token_a()).0, 1_100_000_000);
+
+        // only token B failed, it is kept as a pending deposit
+        amm.remove_liquidity_callback(
+            bob(),
+            near_sdk::json_types::U128(100),
+            100_000_000,
+            100_000_000_000_000_000,
+            Ok(()),
+            Err(PromiseError::Failed),
+        );
+        assert_eq!(amm.get_shares(bob()).0, 100);
+        assert_eq!(
+            amm.get_pending_deposit(bob()),
+            (
+                near_sdk::json_types::U128(0),
+                near_sdk::json_types::U128(100_000_000_000_000_000)
+            )
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Shares are worth 0 of one of the tokens.")]
+    fn test_remove_liquidity_worth_zero_of_a_token() {
+        let mut amm = funded_amm();
+        add_liquidity(&mut amm, bob(), token_a(), 100_000_000);
+        add_liquidity(&mut amm, bob(), token_b(), 100_000_000_000_000_000);
+
+        // one share is worth less than the smallest unit of token A
+        testing_env!(get_owner_ft_transfer_context(bob(), bob(), false));
+        amm.remove_liquidity(near_sdk::json_types::U128(1));
+    }
+
+    #[test]
+    fn test_withdrawals_with_swap_in_flight() {
+        let mut amm = funded_amm();
+        add_liquidity(&mut amm, bob(), token_a(), 100_000_000);
+        add_liquidity(&mut amm, bob(), token_b(), 100_000_000_000_000_000);
+
+        // the swap output has already left the reserves bob withdraws from
+        assert_swapped(swap_one_token_a(&mut amm, ""));
+        let args = function_call_args("swap_callback");
+        assert!(args.contains(r#""token_out_amount":91666666666666666"#));
+        testing_env!(get_owner_ft_transfer_context(bob(), bob(), false));
+        amm.remove_liquidity(amm.get_shares(bob()));
+        testing_env!(get_owner_ft_transfer_context(owner(), owner(), false));
+        amm.set_paused(true);
+        amm.emergency_withdraw(owner());
+        assert_eq!(amm.get_reserves(), (U128(0), U128(0)));
+
+        // committing the swap afterwards does not touch the reserves
+        amm.swap_callback(
+            alice(),
+            0,
+            100_000_000,
+            0,
+            91_666_666_666_666_666,
+            0,
+            Ok(()),
+        );
+        assert_eq!(amm.get_reserves(), (U128(0), U128(0)));
+    }
+
+    #[test]
+    #[should_panic(expected = "Not enough shares.")]
+    fn test_remove_liquidity_without_shares() {
+        let mut amm = funded_amm();
+        testing_env!(get_owner_ft_transfer_context(bob(), bob(), false));
+        amm.remove_liquidity(near_sdk::json_types::U128(1));
+    }
+
+    #[test]
+    fn test_emergency_withdraw() {
+        let mut amm = funded_amm();
+        testing_env!(get_owner_ft_transfer_context(owner(), owner(), false));
+        amm.set_paused(true);
+        amm.emergency_withdraw(owner());
+        assert_eq!(amm.get_reserves(), (U128(0), U128(0)));
+
+        let receipts = near_sdk::test_utils::get_created_receipts();
+        let transfers = receipts
+            .iter()
+            .flat_map(|receipt| receipt.actions.iter())
+            .filter(|action| {
+                matches!(action, near_sdk::mock::VmAction::FunctionCall { function_name, .. }
+                    if function_name == "ft_transfer")
+            })
+            .count();
+        assert_eq!(transfers, 2);
+
+        // a failed transfer restores that reserve only, and the shares are kept
+        testing_env!(
+            get_owner_ft_transfer_context(owner(), owner(), false),
+            near_sdk::VMConfig::test(),
+            near_sdk::RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Successful(vec![]), PromiseResult::Failed],
+        );
+        amm.emergency_withdraw_callback([1_000_000_000, 1_000_000_000_000_000_000], [0, 0]);
+        assert_eq!(
+            amm.get_reserves(),
+            (U128(0), U128(1_000_000_000_000_000_000))
+        );
+    }
+
+    #[test]
+    fn test_emergency_withdraw_clears_shares() {
+        let mut amm = funded_amm();
+        add_liquidity(&mut amm, bob(), token_a(), 100_000_000);
+        add_liquidity(&mut amm, bob(), token_b(), 100_000_000_000_000_000);
+        add_liquidity(&mut amm, alice(), token_a(), 50_000_000);
+        amm.protocol_fees = [10, 0];
+
+        testing_env!(get_owner_ft_transfer_context(owner(), owner(), false));
+        amm.set_paused(true);
+        amm.emergency_withdraw(owner());
+        // the protocol fees are sent along with the reserve
+        assert!(function_call_args("ft_transfer").contains(r#""amount":"1100000010""#));
+        assert_eq!(amm.get_protocol_fees(token_a()).0, 0);
+
+        testing_env!(
+            get_owner_ft_transfer_context(owner(), owner(), false),
+            near_sdk::VMConfig::test(),
+            near_sdk::RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![
+                PromiseResult::Successful(vec![]),
+                PromiseResult::Successful(vec![])
+            ],
+        );
+        amm.emergency_withdraw_callback([1_100_000_000, 1_100_000_000_000_000_000], [10, 0]);
+        assert_eq!(amm.get_total_shares().0, 0);
+        assert_eq!(amm.get_shares(bob()).0, 0);
+        assert_eq!(amm.get_shares(owner()).0, 0);
+        // alice's pending deposit is left to her
+        assert_eq!(amm.get_pending_deposit(alice()).0 .0, 50_000_000);
+
+        // the emptied pool accepts a new first deposit
+        amm.set_paused(false);
+        add_liquidity(&mut amm, alice(), token_b(), 50_000_000_000_000_000);
+        assert_eq!(amm.get_balance(token_a()).0, 50_000_000);
+        assert_eq!(
+            amm.get_total_shares().0,
+            AMM::geometric_mean(50_000_000, 50_000_000_000_000_000)
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the owner can call this method.")]
+    fn test_emergency_withdraw_not_owner() {
+        let mut amm = funded_amm();
+        amm.paused = true;
+        testing_env!(get_owner_ft_transfer_context(alice(), alice(), false));
+        amm.emergency_withdraw(alice());
+    }
+
+    #[test]
+    #[should_panic(expected = "The pool must be paused.")]
+    fn test_emergency_withdraw_not_paused() {
+        let mut amm = funded_amm();
+        testing_env!(get_owner_ft_transfer_context(owner(), owner(), false));
+        amm.emergency_withdraw(owner());
+    }
+
+    #[test]
+    fn test_try_get_metadata() {
+        let mut amm = AMM::new(owner(), token_a(), token_b(), None);
+        assert!(amm.try_get_metadata(token_a()).is_none());
+
+        amm.tokens[0].metadata = Some(token_a_metadata());
+        let metadata = amm.try_get_metadata(token_a()).unwrap();
+        assert_eq!(metadata.symbol, "TA");
+        assert_eq!(metadata.decimals, 8);
+        assert!(amm.try_get_metadata(token_b()).is_none());
+    }
+
+    #[test]
+    fn test_invariant_type() {
+        let amm = AMM::new(owner(), token_a(), token_b(), None);
+        assert_eq!(amm.get_invariant_type(), "constant_product");
+    }
+
+    #[test]
+    fn test_get_metadatas() {
+        let mut amm = AMM::new(owner(), token_a(), token_b(), None);
+        amm.tokens[1].metadata = Some(token_b_metadata());
+
+        let foreign: AccountId = "token_c.testnet".parse().unwrap();
+        let metadatas = amm.get_metadatas(vec![token_b(), foreign, token_a(), token_b()]);
+        assert_eq!(metadatas.len(), 3);
+        assert_eq!(metadatas[0].as_ref().unwrap().symbol, "TB");
+        assert!(metadatas[1].is_none());
+        assert_eq!(metadatas[2].as_ref().unwrap().decimals, 16);
+    }
+
+    #[test]
+    #[should_panic(expected = "Too many tokens requested.")]
+    fn test_get_metadatas_is_bounded() {
+        let amm = AMM::new(owner(), token_a(), token_b(), None);
+        amm.get_metadatas(vec![token_a(); MAX_METADATA_QUERIES + 1]);
+    }
+
+    #[test]
+    fn test_health_check_warns_on_low_near() {
+        let amm = AMM::new(owner(), token_a(), token_b(), None);
+
+        // 1000 bytes lock 0.01 NEAR
+        testing_env!(VMContextBuilder::new()
+            .storage_usage(1000)
+            .account_balance(MIN_AVAILABLE_NEAR * 10)
+            .build());
+        assert_eq!(
+            amm.get_available_near().0,
+            MIN_AVAILABLE_NEAR * 10 - 10_000_000_000_000_000_000_000
+        );
+        assert!(amm.health_check().is_empty());
+
+        testing_env!(VMContextBuilder::new()
+            .storage_usage(1000)
+            .account_balance(MIN_AVAILABLE_NEAR)
+            .build());
+        assert_eq!(
+            amm.get_available_near().0,
+            MIN_AVAILABLE_NEAR - 10_000_000_000_000_000_000_000
+        );
+        assert_eq!(
+            amm.health_check(),
+            vec!["Available NEAR balance is too low to attach transfer deposits."]
+        );
+    }
+
+    #[test]
+    fn test_storage_usage() {
+        testing_env!(VMContextBuilder::new().build());
+        let amm = AMM::new(owner(), token_a(), token_b(), None);
+
+        let storage_usage = amm.get_storage_usage();
+        assert!(storage_usage > 0);
+
+        env::storage_write(b"key", b"value");
+        assert!(amm.get_storage_usage() > storage_usage);
+    }
+
+    #[test]
+    fn test_storage_cap_blocks_new_providers() {
+        let mut amm = funded_amm();
+        add_liquidity(&mut amm, bob(), token_a(), 100_000_000);
+
+        testing_env!(get_owner_ft_transfer_context(owner(), owner(), false));
+        amm.set_max_storage_usage(Some(amm.get_storage_usage()));
+        assert_eq!(amm.get_max_storage_usage(), Some(amm.get_storage_usage()));
+
+        // bob already has a pending deposit, which can still be completed
+        add_liquidity(&mut amm, bob(), token_b(), 100_000_000_000_000_000);
+        assert!(amm.get_shares(bob()).0 > 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Storage cap reached, new liquidity providers are blocked.")]
+    fn test_storage_cap_rejects_new_provider() {
+        let mut amm = funded_amm();
+        testing_env!(get_owner_ft_transfer_context(owner(), owner(), false));
+        amm.set_max_storage_usage(Some(amm.get_storage_usage()));
+
+        add_liquidity(&mut amm, alice(), token_a(), 100_000_000);
+    }
+
+    #[test]
+    fn test_set_token_name() {
+        let mut amm = funded_amm();
+        testing_env!(get_owner_ft_transfer_context(owner(), owner(), false));
+        amm.set_token_name(token_a(), "Token A".to_string());
+
+        let metadata = amm.get_metadata(token_a());
+        assert_eq!(metadata.name, "Token A");
+        assert_eq!(metadata.symbol, "TA");
+        assert_eq!(metadata.decimals, 8);
+    }
+
+    #[test]
+    fn test_set_token_symbol() {
+        let mut amm = funded_amm();
+        testing_env!(get_owner_ft_transfer_context(owner(), owner(), false));
+        amm.set_token_symbol(token_b(), "TKB".to_string());
+
+        let metadata = amm.get_metadata(token_b());
+        assert_eq!(metadata.name, "token_b");
+        assert_eq!(metadata.symbol, "TKB");
+        assert_eq!(metadata.decimals, 16);
+    }
+
+    #[test]
+    fn test_allowed_swappers() {
+        let mut amm = funded_amm();
+        // public by default
+        assert!(amm.is_allowed_swapper(alice()));
+        assert_swapped(swap_one_token_a(&mut amm, ""));
+
+        testing_env!(get_owner_ft_transfer_context(owner(), owner(), false));
+        amm.add_allowed_swapper(alice());
+        assert_swapped(swap_one_token_a(&mut amm, ""));
+
+        testing_env!(get_owner_ft_transfer_context(bob(), token_a(), false));
+        assert_refunded(
+            amm.ft_on_transfer(bob(), U128(100_000_000), "".to_string()),
+            100_000_000,
+        );
+        assert!(get_logs().contains(&"The sender is not allowed to swap in this pool.".to_string()));
+        // only the two priced swaps moved the reserve
+        assert_eq!(amm.get_balance(token_a()).0, 1_200_000_000);
+
+        testing_env!(get_owner_ft_transfer_context(owner(), owner(), false));
+        amm.remove_allowed_swapper(alice());
+        assert_refunded(swap_one_token_a(&mut amm, ""), 100_000_000);
+
+        // back to public
+        testing_env!(get_owner_ft_transfer_context(owner(), owner(), false));
+        amm.clear_allowed_swappers();
+        assert!(amm.is_allowed_swapper(bob()));
+        assert_swapped(swap_one_token_a(&mut amm, ""));
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the owner can call this method.")]
+    fn test_add_allowed_swapper_not_owner() {
+        let mut amm = funded_amm();
+        testing_env!(get_owner_ft_transfer_context(alice(), alice(), false));
+        amm.add_allowed_swapper(alice());
+    }
+
+    #[test]
+    fn test_set_transfer_gas() {
+        let mut amm = funded_amm();
+        assert_eq!(amm.get_transfer_gas(), 10);
+        testing_env!(get_owner_ft_transfer_context(owner(), owner(), false));
+        amm.set_transfer_gas(25);
+        assert_eq!(amm.transfer_gas, Gas(25_000_000_000_000));
+
+        assert_swapped(swap_one_token_a(&mut amm, ""));
+        let transfer_gas = near_sdk::test_utils::get_created_receipts()
+            .into_iter()
+            .flat_map(|receipt| receipt.actions)
+            .find_map(|action| match action {
+                near_sdk::mock::VmAction::FunctionCall {
+                    function_name, gas, ..
+                } if function_name == "ft_transfer" => Some(gas),
+                _ => None,
+            });
+        assert_eq!(transfer_gas, Some(Gas(25_000_000_000_000)));
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the owner can call this method.")]
+    fn test_set_transfer_gas_not_owner() {
+        let mut amm = funded_amm();
+        testing_env!(get_owner_ft_transfer_context(alice(), alice(), false));
+        amm.set_transfer_gas(25);
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the owner can call this method.")]
+    fn test_set_token_symbol_not_owner() {
+        let mut amm = funded_amm();
+        testing_env!(get_owner_ft_transfer_context(alice(), alice(), false));
+        amm.set_token_symbol(token_a(), "FAKE".to_string());
+    }
+
+    #[test]
+    #[should_panic(expected = "Metadata is not initialized!")]
+    fn test_set_token_name_without_metadata() {
+        let mut amm = AMM::new(owner(), token_a(), token_b(), None);
+        testing_env!(get_owner_ft_transfer_context(owner(), owner(), false));
+        amm.set_token_name(token_a(), "Token A".to_string());
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_get_ratio_without_metadata() {
+        let amm = AMM::new(owner(), token_a(), token_b(), None);
+        amm.get_ratio();
+    }
+
+    #[test]
+    fn test_ratio() {
+        let mut amm = AMM::new(owner(), token_a(), token_b(), None);
+        amm.tokens[0].metadata = Some(token_a_metadata());
+        amm.tokens[0].balance = 1_000_000_000;
+        amm.tokens[1].metadata = Some(token_b_metadata());
+        amm.tokens[1].balance = 1_000_000_000_000_000_000;
+        assert_eq!(amm.get_ratio(), near_sdk::json_types::U128(1000));
+    }
+
+    #[test]
+    fn test_ratio_with_sub_unit_balances() {
+        let mut amm = AMM::new(owner(), token_a(), token_b(), None);
+        amm.tokens[0].metadata = Some(token_a_metadata());
+        amm.tokens[1].metadata = Some(token_b_metadata());
+
+        // 0.5 TA * 0.5 TB is positive even though both balances are below one unit
+        amm.tokens[0].balance = 50_000_000;
+        amm.tokens[1].balance = 5_000_000_000_000_000;
+        assert_eq!(amm.get_ratio(), near_sdk::json_types::U128(1));
+
+        // 1.5 TA * 1.5 TB = 2.25
+        amm.tokens[0].balance = 150_000_000;
+        amm.tokens[1].balance = 15_000_000_000_000_000;
+        assert_eq!(amm.get_ratio(), near_sdk::json_types::U128(2));
+
+        // an empty side still results in 0
+        amm.tokens[0].balance = 0;
+        assert_eq!(amm.get_ratio(), near_sdk::json_types::U128(0));
+    }
+
+    #[test]
+    fn test_pool_info() {
+        let mut amm = AMM::new(owner(), token_a(), token_b(), None);
+        amm.tokens[0].metadata = Some(token_a_metadata());
+        owner_deposit_both_tokens(&mut amm);
+
+        let info = near_sdk::serde_json::to_value(amm.get_pool_info()).unwrap();
+        let (reserve_a, reserve_b) = amm.get_reserves();
+        assert_eq!(
+            info,
+            near_sdk::serde_json::json!({
+                "owner": amm.get_owner(),
+                "token_a": token_a(),
+                "token_b": token_b(),
+                "metadata_a": amm.try_get_metadata(token_a()),
+                "metadata_b": amm.try_get_metadata(token_b()),
+                "reserve_a": reserve_a,
+                "reserve_b": reserve_b,
+            })
+        );
+        assert_eq!(info["metadata_a"]["symbol"], "TA");
+        assert!(info["metadata_b"].is_null());
+        assert_eq!(info["reserve_a"], "1000000000");
+    }
+
+    #[test]
+    fn test_get_reserves() {
+        let mut amm = AMM::new(owner(), token_a(), token_b(), None);
+        amm.tokens[0].metadata = Some(token_a_metadata());
+        amm.tokens[1].metadata = Some(token_b_metadata());
+
+        // 0.001 TA * 0.001 TB truncates to 0 in whole tokens, the reserves keep the amounts
+        amm.tokens[0].balance = 100_000;
+        amm.tokens[1].balance = 10_000_000_000_000;
+        assert_eq!(
+            (U256::from(100_000u128) * U256::from(10_000_000_000_000u128) / U256::exp10(24))
+                .as_u128(),
+            0
+        );
+        assert_eq!(amm.get_ratio(), near_sdk::json_types::U128(1));
+        assert_eq!(
+            amm.get_reserves(),
+            (
+                near_sdk::json_types::U128(100_000),
+                near_sdk::json_types::U128(10_000_000_000_000)
+            )
+        );
+    }
+
+    #[test]
+    fn test_spot_price() {
+        let amm = funded_amm();
+
+        // 10 TA against 100 TB
+        let spot_price = amm.get_spot_price(token_a()).0;
+        assert_eq!(spot_price, 10 * PRICE_PRECISION);
+        assert_eq!(amm.get_spot_price(token_b()).0, PRICE_PRECISION / 10);
+
+        // the rate of a tiny swap converges to the spot price from below
+        let amount_in = 1_000;
+        let amount_out = amm
+            .get_quote(token_a(), near_sdk::json_types::U128(amount_in), None)
+            .0;
+        let rate = (U256::from(amount_out) * U256::exp10(8) * U256::from(PRICE_PRECISION)
+            / (U256::from(amount_in) * U256::exp10(16)))
+        .as_u128();
+        assert!(rate <= spot_price);
+        assert!((spot_price - rate) * 100_000 < spot_price);
+    }
+
+    #[test]
+    #[should_panic(expected = "Cannot compute the price for an empty pool.")]
+    fn test_spot_price_for_empty_pool() {
+        let mut amm = funded_amm();
+        amm.tokens[1].balance = 0;
+        amm.get_spot_price(token_a());
+    }
+
+    #[test]
+    fn test_spread() {
+        // without a fee a round trip only loses to rounding
+        let mut amm = funded_amm();
+        assert_eq!(amm.get_spread_bps(), 0);
+
+        // rounding dominates in a shallow pool
+        amm.tokens[0].balance = 10_000;
+        amm.tokens[1].balance = 7;
+        assert!(amm.get_spread_bps() > 0);
+    }
+
+    #[test]
+    fn test_spread_with_default_fee() {
+        let mut amm = funded_amm();
+        amm.fee_bps = DEFAULT_FEE_BPS;
+
+        // a round trip pays the fee twice, the price impact of the small reference is negligible
+        let spread_bps = amm.get_spread_bps();
+        assert!((2 * DEFAULT_FEE_BPS..=2 * DEFAULT_FEE_BPS + 1).contains(&spread_bps));
+    }
+
+    #[test]
+    fn test_effective_price() {
+        let amm = funded_amm();
+
+        // 10 TA against 100 TB
+        let mid_price = 10 * PRICE_PRECISION;
+
+        // 1 TA buys 90909090909090909 TB units, i.e. 9.09 TB
+        let effective_price =
+            amm.get_effective_price(token_a(), near_sdk::json_types::U128(100_000_000));
+        assert_eq!(effective_price.0, 9_090_909_090_909_090_900);
+
+        // a tiny swap gets close to the mid price, a larger one gets less
+        let small_price = amm.get_effective_price(token_a(), near_sdk::json_types::U128(100_000));
+        assert!(small_price.0 < mid_price && small_price.0 > effective_price.0);
+    }
+
+    #[test]
+    fn test_effective_price_includes_fee() {
+        let mut amm = funded_amm();
+        amm.fee_bps = DEFAULT_FEE_BPS;
+        let mid_price = 10 * PRICE_PRECISION;
+
+        // a small swap gets about the fee below the mid price, plus 1 bps of price impact
+        let effective_price =
+            amm.get_effective_price(token_a(), near_sdk::json_types::U128(100_000));
+        let discount_bps = (mid_price - effective_price.0) * BPS_DENOMINATOR / mid_price;
+        assert!((30..=31).contains(&discount_bps));
+    }
+
+    #[test]
+    fn test_simulate_swap_sequence() {
+        let amm = funded_amm();
+
+        // no swaps returns the current price
+        assert_eq!(
+            amm.simulate_swap_sequence(vec![]),
+            near_sdk::json_types::U128(10 * PRICE_PRECISION)
+        );
+
+        // balances after the swaps: 1_138_031_652 TA and 878_710_182_092_864_704 TB
+        let swaps = vec![
+            (token_a(), near_sdk::json_types::U128(100_000_000)),
+            (
+                token_b(),
+                near_sdk::json_types::U128(10_000_000_000_000_000),
+            ),
+            (token_a(), near_sdk::json_types::U128(50_000_000)),
+        ];
+        assert_eq!(
+            amm.simulate_swap_sequence(swaps),
+            near_sdk::json_types::U128(7_721_315_839_929_421_435)
+        );
+
+        // the simulation does not modify the pool
+        assert_eq!(
+            amm.get_balance(token_a()),
+            near_sdk::json_types::U128(1_000_000_000)
+        );
+        assert_eq!(
+            amm.get_balance(token_b()),
+            near_sdk::json_types::U128(1_000_000_000_000_000_000)
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Cannot quote an empty pool.")]
+    fn test_effective_price_empty_pool() {
+        let amm = AMM::new(owner(), token_a(), token_b(), None);
+        amm.get_effective_price(token_a(), near_sdk::json_types::U128(100));
+    }
+
+    #[test]
+    #[should_panic(expected = "Cannot compute the price for an empty pool.")]
+    fn test_simulate_swap_sequence_empty_pool() {
+        let amm = AMM::new(owner(), token_a(), token_b(), None);
+        amm.simulate_swap_sequence(vec![(token_a(), near_sdk::json_types::U128(1))]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Too many swaps to simulate.")]
+    fn test_simulate_too_many_swaps() {
+        let amm = funded_amm();
+        amm.simulate_swap_sequence(vec![
+            (token_a(), near_sdk::json_types::U128(1));
+            MAX_SIMULATED_SWAPS + 1
+        ]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_swap_amount_zero() {
+        let mut amm = AMM::new(owner(), token_a(), token_b(), None);
+        amm.swap(alice(), 0, 0, 0, SwapMsg::default());
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_swap_for_zero_tokens() {
+        let mut amm = AMM::new(owner(), token_a(), token_b(), None);
+        amm.tokens[0].metadata = Some(token_a_metadata());
+        amm.tokens[0].balance = 1_000_000_000;
+        amm.swap(alice(), 0, 10, 0, SwapMsg::default());
+    }
+
+    #[test]
+    fn test_ft_on_transfer() {
+        let mut amm = AMM::new(owner(), token_a(), token_b(), None);
+
+        // owner deposits token_a
+        testing_env!(get_owner_ft_transfer_context(owner(), token_a(), false));
+        amm.tokens[0].metadata = Some(token_a_metadata());
+        amm.ft_on_transfer(
+            owner(),
+            near_sdk::json_types::U128(1_000_000_000),
+            "".to_string(),
+        );
+        assert_eq!(
+            amm.get_balance(token_a()),
+            near_sdk::json_types::U128(1_000_000_000)
+        );
+
+        // owner deposits token_b
+        testing_env!(get_owner_ft_transfer_context(owner(), token_b(), false));
+        amm.tokens[1].metadata = Some(token_b_metadata());
+        amm.ft_on_transfer(
+            owner(),
+            near_sdk::json_types::U128(1_000_000_000_000_000_000),
+            "".to_string(),
+        );
+        assert_eq!(
+            amm.get_balance(token_b()),
+            near_sdk::json_types::U128(1_000_000_000_000_000_000)
+        );
+
+        // ratio gets updated accordingly
+        assert_eq!(amm.get_ratio(), near_sdk::json_types::U128(1000));
+
+        // user swaps tokens
+        testing_env!(get_owner_ft_transfer_context(alice(), token_b(), false));
+        amm.ft_on_transfer(
+            alice(),
+            near_sdk::json_types::U128(100_000_000_000_000_000),
+            "".to_string(),
+        );
+
+        // ratio does not change after swap
+        assert_eq!(amm.get_ratio(), near_sdk::json_types::U128(1000));
+
+        // balances change as soon as the swap is priced, before the output transfer resolves
+        assert_eq!(
+            amm.get_balance(token_a()),
+            near_sdk::json_types::U128(909_338_911)
+        );
+        assert_eq!(
+            amm.get_balance(token_b()),
+            near_sdk::json_types::U128(1_100_000_000_000_000_000)
+        );
+
+        // owner deposits more token_b
+        testing_env!(get_owner_ft_transfer_context(owner(), token_b(), false));
+        amm.ft_on_transfer(
+            owner(),
+            near_sdk::json_types::U128(1_000_000_000_000_000_000),
+            "".to_string(),
+        );
+        assert_eq!(
+            amm.get_balance(token_b()),
+            near_sdk::json_types::U128(2_100_000_000_000_000_000)
+        );
+
+        // ratio gets updated accordingly
+        assert_eq!(amm.get_ratio(), near_sdk::json_types::U128(1909));
+    }
+
+    #[test]
+    #[should_panic(expected = "Deposit creates an imbalanced initial ratio.")]
+    fn test_imbalanced_init_deposit() {
+        let mut amm = AMM::new(owner(), token_a(), token_b(), None);
+        amm.tokens[0].metadata = Some(token_a_metadata());
+        amm.tokens[1].metadata = Some(token_b_metadata());
+
+        // at most 100:1 between the two reserves
+        testing_env!(get_owner_ft_transfer_context(owner(), owner(), false));
+        amm.set_max_init_imbalance_bps(Some(near_sdk::json_types::U128(1_000_000)));
+
+        // owner deposits 1 token_a
+        testing_env!(get_owner_ft_transfer_context(owner(), token_a(), false));
+        amm.ft_on_transfer(
+            owner(),
+            near_sdk::json_types::U128(100_000_000),
+            "".to_string(),
+        );
+
+        // 100 token_b is still within the limit
+        testing_env!(get_owner_ft_transfer_context(owner(), token_b(), false));
+        amm.ft_on_transfer(
+            owner(),
+            near_sdk::json_types::U128(1_000_000_000_000_000_000),
+            "".to_string(),
+        );
+
+        // another token_b pushes the ratio past 100:1
+        amm.ft_on_transfer(
+            owner(),
+            near_sdk::json_types::U128(10_000_000_000_000_000),
+            "".to_string(),
+        );
+    }
+
+    #[test]
+    fn test_imbalance_not_enforced_after_swap() {
+        let mut amm = AMM::new(owner(), token_a(), token_b(), None);
+        amm.tokens[0].metadata = Some(token_a_metadata());
+        amm.tokens[1].metadata = Some(token_b_metadata());
+        amm.tokens[0].balance = 100_000_000;
+        amm.tokens[1].balance = 100_000_000_000_000_000;
+        amm.has_swapped = true;
+
+        testing_env!(get_owner_ft_transfer_context(owner(), owner(), false));
+        amm.set_max_init_imbalance_bps(Some(near_sdk::json_types::U128(1_000_000)));
+
+        testing_env!(get_owner_ft_transfer_context(owner(), token_b(), false));
+        amm.ft_on_transfer(
+            owner(),
+            near_sdk::json_types::U128(1_000_000_000_000_000_000),
+            "".to_string(),
+        );
+        assert_eq!(
+            amm.get_balance(token_b()),
+            near_sdk::json_types::U128(1_100_000_000_000_000_000)
+        );
+    }
+
+    // Swapping 1 TA returns 90909090909090909 TB, 909 bps less than the 0.1 TB spot value
+    fn swap_one_token_a(amm: &mut AMM, msg: &str) -> PromiseOrValue<U128> {
+        testing_env!(get_owner_ft_transfer_context(alice(), token_a(), false));
+        amm.ft_on_transfer(
+            alice(),
+            near_sdk::json_types::U128(100_000_000),
+            msg.to_string(),
+        )
+    }
+
+    #[test]
+    fn test_swap_with_min_amount_out() {
+        let mut amm = funded_amm();
+        assert_swapped(swap_one_token_a(
+            &mut amm,
+            r#"{"min_amount_out": "90909090909090909"}"#,
+        ));
+        assert_refunded(
+            swap_one_token_a(&mut amm, r#"{"min_amount_out": "90909090909090910"}"#),
+            100_000_000,
+        );
+    }
 
-near call ft.predeployed.examples.testnet ft_transfer '{"receiver_id": "'dev-1672856405734-99444394530569'", "amount": "1000"}' --accountId ammdev.testnet --depositYocto 1
-near call ft.predeployed.examples.testnet ft_transfer_call '{"receiver_id": "'dev-1672856405734-99444394530569'", "amount": "1000", "msg": ""}' --accountId ammdev.testnet --depositYocto 1
-near view ft.predeployed.examples.testnet ft_balance_of '{"account_id": "'dev-1672856405734-99444394530569'"}'  --account-id ammdev.testnet
+    #[test]
+    #[should_panic(expected = "Invalid swap msg.")]
+    fn test_swap_with_malformed_msg() {
+        let mut amm = funded_amm();
+        swap_one_token_a(&mut amm, r#"{"min_amount_out": 12345"#);
+    }
 
-near create-account bob.ammdev.testnet --masterAccount ammdev.testnet --initialBalance 10
-near call ft.predeployed.examples.testnet storage_deposit '' --accountId bob.ammdev.testnet --amount 0.00125
+    #[test]
+    fn test_swap_with_max_slippage_bps() {
+        let mut amm = funded_amm();
+        testing_env!(get_owner_ft_transfer_context(owner(), owner(), false));
+        amm.set_fee_bps(30);
 
-near view ft.predeployed.examples.testnet ft_balance_of '{"account_id": "'ammdev.testnet'"}'  --account-id ammdev.testnet
-near call ft.predeployed.examples.testnet ft_mint '{"account_id": "'ammdev.testnet'", "amount": "10000000000000000000000"}' --accountId ammdev.testnet
+        // the quote already includes the fee and the price impact, so a swap at the quote
+        // passes even without any slippage allowed
+        assert_swapped(swap_one_token_a(&mut amm, r#"{"max_slippage_bps": 0}"#));
 
-near call ft.predeployed.examples.testnet ft_transfer '{"receiver_id": "'bob.ammdev.testnet'", "amount": "19"}' --accountId ammdev.testnet --depositYocto 1
+        // 1% below the quote of 90661089388014913 is looser than this min_amount_out
+        assert_refunded(
+            swap_one_token_a(
+                &mut amm,
+                r#"{"min_amount_out": "90661089388014914", "max_slippage_bps": 100}"#,
+            ),
+            100_000_000,
+        );
+    }
 
-*/
+    #[test]
+    fn test_swap_with_max_value_loss_bps() {
+        let mut amm = funded_amm();
+        testing_env!(get_owner_ft_transfer_context(owner(), owner(), false));
+        amm.set_fee_bps(30);
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use near_sdk::test_utils::VMContextBuilder;
-    use near_sdk::{testing_env, VMContext};
+        // 90661089388014913 out of a spot value of 10^17 loses ~934 bps
+        assert_refunded(
+            swap_one_token_a(&mut amm, r#"{"max_value_loss_bps": 900}"#),
+            100_000_000,
+        );
+        assert_swapped(swap_one_token_a(&mut amm, r#"{"max_value_loss_bps": 950}"#));
 
-    fn get_owner_ft_transfer_context(
-        signer: AccountId,
-        predecessor_account_id: AccountId,
-        is_view: bool,
-    ) -> VMContext {
-        VMContextBuilder::new()
-            .signer_account_id(signer)
-            .predecessor_account_id(predecessor_account_id)
-            .is_view(is_view)
-            .build()
+        // both bounds are enforced, the stricter one refunds
+        let mut amm = funded_amm();
+        testing_env!(get_owner_ft_transfer_context(owner(), owner(), false));
+        amm.set_fee_bps(30);
+        assert_refunded(
+            swap_one_token_a(
+                &mut amm,
+                r#"{"max_slippage_bps": 950, "max_value_loss_bps": 900}"#,
+            ),
+            100_000_000,
+        );
     }
 
-    fn owner() -> AccountId {
-        return "owner.testnet".to_string().parse().unwrap();
-    }
+    #[test]
+    fn test_swap_with_max_total_cost_bps() {
+        let mut amm = funded_amm();
+        testing_env!(get_owner_ft_transfer_context(owner(), owner(), false));
+        amm.set_fee_bps(30);
 
-    fn alice() -> AccountId {
-        return "alice.testnet".to_string().parse().unwrap();
+        // the slippage is acceptable, but 30 bps of fee and ~906 bps of impact do not fit in
+        // a 300 bps total cost
+        assert_refunded(
+            swap_one_token_a(
+                &mut amm,
+                r#"{"max_slippage_bps": 950, "max_total_cost_bps": 300}"#,
+            ),
+            100_000_000,
+        );
+        assert!(get_logs().contains(&"Total cost limit exceeded.".to_string()));
+        assert_swapped(swap_one_token_a(
+            &mut amm,
+            r#"{"max_slippage_bps": 950, "max_total_cost_bps": 940}"#,
+        ));
     }
 
-    fn token_a() -> AccountId {
-        return "token_a.testnet".to_string().parse().unwrap();
-    }
+    #[test]
+    fn test_swap_with_max_impact_bps() {
+        let mut amm = funded_amm();
+        testing_env!(get_owner_ft_transfer_context(owner(), owner(), false));
+        amm.set_fee_bps(30);
 
-    fn token_a_metadata() -> TokenMetadata {
-        return TokenMetadata::new(
-            "token_a".to_string().parse().unwrap(),
-            "TA".to_string().parse().unwrap(),
-            8,
+        // 0.01 TA moves the price by ~10 bps
+        testing_env!(get_owner_ft_transfer_context(alice(), token_a(), false));
+        assert_swapped(amm.ft_on_transfer(
+            alice(),
+            U128(1_000_000),
+            r#"{"max_impact_bps": 500}"#.to_string(),
+        ));
+
+        // 1 TA moves it by ~906 bps, the fee is not part of the impact
+        assert_refunded(
+            swap_one_token_a(&mut amm, r#"{"max_impact_bps": 500}"#),
+            100_000_000,
         );
+        assert_swapped(swap_one_token_a(&mut amm, r#"{"max_impact_bps": 910}"#));
     }
 
-    fn token_b() -> AccountId {
-        return "token_b.testnet".to_string().parse().unwrap();
+    #[test]
+    fn test_swap_with_deadline() {
+        let mut amm = funded_amm();
+        let mut context = VMContextBuilder::new();
+        context
+            .predecessor_account_id(token_a())
+            .signer_account_id(alice());
+        let swap = |amm: &mut AMM| {
+            amm.ft_on_transfer(
+                alice(),
+                near_sdk::json_types::U128(100_000_000),
+                r#"{"deadline": "1000"}"#.to_string(),
+            )
+        };
+
+        testing_env!(context.block_timestamp(1_000).build());
+        assert_swapped(swap(&mut amm));
+
+        testing_env!(context.block_timestamp(1_001).build());
+        assert_refunded(swap(&mut amm), 100_000_000);
     }
 
-    fn token_b_metadata() -> TokenMetadata {
-        return TokenMetadata::new(
-            "token_b".to_string().parse().unwrap(),
-            "TB".to_string().parse().unwrap(),
-            16,
+    #[test]
+    fn test_swap_with_both_slippage_bounds() {
+        let mut amm = funded_amm();
+        testing_env!(get_owner_ft_transfer_context(owner(), owner(), false));
+        amm.set_fee_bps(30);
+
+        // the absolute bound is stricter
+        assert_refunded(
+            swap_one_token_a(
+                &mut amm,
+                r#"{"min_amount_out": "95000000000000000", "max_slippage_bps": 1000}"#,
+            ),
+            100_000_000,
         );
+
+        // both bounds are met
+        assert_swapped(swap_one_token_a(
+            &mut amm,
+            r#"{"min_amount_out": "90000000000000000", "max_slippage_bps": 1000}"#,
+        ));
+
+        // the relative bound is stricter, and the swap executes at the quote
+        assert_swapped(swap_one_token_a(
+            &mut amm,
+            r#"{"min_amount_out": "1", "max_slippage_bps": 0}"#,
+        ));
     }
 
     #[test]
-    fn test_init() {
-        let amm = AMM::new(owner(), token_a(), token_b());
-        assert_eq!(amm.owner, owner());
-        assert_eq!(amm.tokens[0].address, token_a());
-        assert_eq!(amm.tokens[1].address, token_b());
+    fn test_deposit_above_max_single_deposit() {
+        let mut amm = AMM::new(owner(), token_a(), token_b(), None);
+
+        testing_env!(get_owner_ft_transfer_context(owner(), owner(), false));
+        amm.set_max_single_deposit(token_a(), Some(near_sdk::json_types::U128(1_000)));
+        assert_eq!(
+            amm.get_max_single_deposit(token_a()),
+            Some(near_sdk::json_types::U128(1_000))
+        );
+        assert_eq!(amm.get_max_single_deposit(token_b()), None);
+
+        // owner deposits more token_a than the cap and gets the excess back
+        testing_env!(get_owner_ft_transfer_context(owner(), token_a(), false));
+        assert_refunded(
+            amm.ft_on_transfer(owner(), near_sdk::json_types::U128(1_500), "".to_string()),
+            500,
+        );
+        assert_eq!(
+            amm.get_balance(token_a()),
+            near_sdk::json_types::U128(1_000)
+        );
+
+        // deposits within the cap are fully accepted
+        assert_refunded(
+            amm.ft_on_transfer(owner(), near_sdk::json_types::U128(800), "".to_string()),
+            0,
+        );
+        assert_eq!(
+            amm.get_balance(token_a()),
+            near_sdk::json_types::U128(1_800)
+        );
     }
 
     #[test]
-    #[should_panic]
-    fn test_get_ratio_without_metadata() {
-        let amm = AMM::new(owner(), token_a(), token_b());
-        amm.get_ratio();
+    #[should_panic(expected = "Only the owner can call this method.")]
+    fn test_set_max_single_deposit_not_owner() {
+        let mut amm = AMM::new(owner(), token_a(), token_b(), None);
+        testing_env!(get_owner_ft_transfer_context(alice(), alice(), false));
+        amm.set_max_single_deposit(token_a(), Some(near_sdk::json_types::U128(1_000)));
     }
 
     #[test]
-    fn test_ratio() {
-        let mut amm = AMM::new(owner(), token_a(), token_b());
-        amm.tokens[0].metadata = Some(token_a_metadata());
-        amm.tokens[0].balance = 1_000_000_000;
-        amm.tokens[1].metadata = Some(token_b_metadata());
-        amm.tokens[1].balance = 1_000_000_000_000_000_000;
-        assert_eq!(amm.get_ratio(), near_sdk::json_types::U128(1000));
+    fn test_owner_can_swap() {
+        let mut amm = funded_amm();
+
+        // by default every owner transfer is a deposit, even with a swap msg
+        testing_env!(get_owner_ft_transfer_context(owner(), token_a(), false));
+        assert_refunded(
+            amm.ft_on_transfer(owner(), near_sdk::json_types::U128(1_000), "{}".to_string()),
+            0,
+        );
+        assert_eq!(
+            amm.get_balance(token_a()),
+            near_sdk::json_types::U128(1_000_001_000)
+        );
+
+        testing_env!(get_owner_ft_transfer_context(owner(), owner(), false));
+        amm.set_owner_can_swap(true);
+        assert!(amm.get_owner_can_swap());
+
+        // owner transfers with a swap msg are swapped, the input is credited when priced
+        testing_env!(get_owner_ft_transfer_context(owner(), token_a(), false));
+        assert_swapped(amm.ft_on_transfer(
+            owner(),
+            near_sdk::json_types::U128(1_000),
+            "{}".to_string(),
+        ));
+        assert_eq!(
+            amm.get_balance(token_a()),
+            near_sdk::json_types::U128(1_000_002_000)
+        );
+
+        // owner transfers without a msg are still deposits
+        assert_refunded(
+            amm.ft_on_transfer(owner(), near_sdk::json_types::U128(1_000), "".to_string()),
+            0,
+        );
+        assert_eq!(
+            amm.get_balance(token_a()),
+            near_sdk::json_types::U128(1_000_003_000)
+        );
     }
 
     #[test]
-    #[should_panic]
-    fn test_swap_amount_zero() {
-        let mut amm = AMM::new(owner(), token_a(), token_b());
-        amm.swap(alice(), 0, 0);
+    fn test_failed_swap_callback_keeps_balances() {
+        let mut amm = funded_amm();
+        amm.apply_swap(0, 100_000_000, 90_909_090_909_090_909, 0);
+
+        // the output transfer failed, the input is refunded and the reserves are restored
+        assert_refunded(
+            amm.swap_callback(
+                alice(),
+                0,
+                100_000_000,
+                5,
+                90_909_090_909_090_909,
+                0,
+                Err(PromiseError::Failed),
+            ),
+            100_000_005,
+        );
+        assert_eq!(
+            amm.get_balance(token_a()),
+            near_sdk::json_types::U128(1_000_000_000)
+        );
+        assert_eq!(
+            amm.get_balance(token_b()),
+            near_sdk::json_types::U128(1_000_000_000_000_000_000)
+        );
+        assert!(!amm.has_swapped);
     }
 
     #[test]
-    #[should_panic]
-    fn test_swap_for_zero_tokens() {
-        let mut amm = AMM::new(owner(), token_a(), token_b());
-        amm.tokens[0].metadata = Some(token_a_metadata());
-        amm.tokens[0].balance = 1_000_000_000;
-        amm.swap(alice(), 0, 10);
+    fn test_swap_callback_updates_balances_by_side() {
+        let mut amm = funded_amm();
+
+        // successful swap from token_b to token_a
+        amm.apply_swap(1, 100_000_000_000_000_000, 90_909_090, 0);
+        assert_refunded(
+            amm.swap_callback(
+                alice(),
+                1,
+                100_000_000_000_000_000,
+                0,
+                90_909_090,
+                0,
+                Ok(()),
+            ),
+            0,
+        );
+        assert_eq!(
+            amm.get_balance(token_a()),
+            near_sdk::json_types::U128(909_090_910)
+        );
+        assert_eq!(
+            amm.get_balance(token_b()),
+            near_sdk::json_types::U128(1_100_000_000_000_000_000)
+        );
     }
 
     #[test]
-    fn test_ft_on_transfer() {
-        let mut amm = AMM::new(owner(), token_a(), token_b());
+    fn test_swap_forward_callback() {
+        let mut amm = funded_amm();
 
-        // owner deposits token_a
-        testing_env!(get_owner_ft_transfer_context(owner(), token_a(), false));
-        amm.tokens[0].metadata = Some(token_a_metadata());
-        amm.ft_on_transfer(
-            owner(),
-            near_sdk::json_types::U128(1_000_000_000),
-            "".to_string(),
+        // forwarding failed, the input is returned
+        amm.apply_swap(0, 100_000_000, 90_909_090_909_090_909, 0);
+        assert_refunded(
+            amm.swap_forward_callback(
+                alice(),
+                bob(),
+                0,
+                100_000_000,
+                0,
+                90_909_090_909_090_909,
+                0,
+                Err(PromiseError::Failed),
+            ),
+            100_000_000,
         );
         assert_eq!(
             amm.get_balance(token_a()),
             near_sdk::json_types::U128(1_000_000_000)
         );
 
-        // owner deposits token_b
-        testing_env!(get_owner_ft_transfer_context(owner(), token_b(), false));
-        amm.tokens[1].metadata = Some(token_b_metadata());
-        amm.ft_on_transfer(
-            owner(),
-            near_sdk::json_types::U128(1_000_000_000_000_000_000),
-            "".to_string(),
+        // the forward target kept part of the output, the swap is committed
+        amm.apply_swap(0, 100_000_000, 90_909_090_909_090_909, 0);
+        assert_refunded(
+            amm.swap_forward_callback(
+                alice(),
+                bob(),
+                0,
+                100_000_000,
+                0,
+                90_909_090_909_090_909,
+                0,
+                Ok(near_sdk::json_types::U128(50_000_000_000_000_000)),
+            ),
+            0,
+        );
+        assert_eq!(
+            amm.get_balance(token_a()),
+            near_sdk::json_types::U128(1_100_000_000)
         );
         assert_eq!(
             amm.get_balance(token_b()),
-            near_sdk::json_types::U128(1_000_000_000_000_000_000)
+            near_sdk::json_types::U128(909_090_909_090_909_091)
+        );
+        assert!(
+            function_call_args("forward_refund_callback").contains(r#""amount":40909090909090909"#)
         );
 
-        // ratio gets updated accordingly
-        assert_eq!(amm.get_ratio(), near_sdk::json_types::U128(1000));
-
-        // user swaps tokens
-        testing_env!(get_owner_ft_transfer_context(alice(), token_b(), false));
-        amm.ft_on_transfer(
+        // the sender could not receive the unused output, it is credited as a pending deposit
+        amm.forward_refund_callback(
             alice(),
-            near_sdk::json_types::U128(100_000_000_000_000_000),
-            "".to_string(),
+            1,
+            40_909_090_909_090_909,
+            Err(PromiseError::Failed),
         );
+        assert_eq!(
+            amm.get_pending_deposit(alice()).1 .0,
+            40_909_090_909_090_909
+        );
+        assert_eq!(amm.pending_totals, [0, 40_909_090_909_090_909]);
+    }
 
-        // ratio does not change after swap
-        assert_eq!(amm.get_ratio(), near_sdk::json_types::U128(1000));
+    #[test]
+    fn test_swap_with_platform_fee() {
+        let mut amm = funded_amm();
+        assert_swapped(swap_one_token_a(
+            &mut amm,
+            r#"{"platform_fee_bps": 50, "platform_account": "bob.testnet"}"#,
+        ));
+
+        // 0.5% of the 90909090909090909 output goes to the platform
+        let receipts = near_sdk::test_utils::get_created_receipts();
+        let transfers: Vec<String> = receipts
+            .iter()
+            .filter(|receipt| receipt.receiver_id == token_b())
+            .flat_map(|receipt| receipt.actions.iter())
+            .filter_map(|action| match action {
+                near_sdk::mock::VmAction::FunctionCall {
+                    function_name,
+                    args,
+                    ..
+                } if function_name == "ft_transfer" => {
+                    Some(String::from_utf8(args.clone()).unwrap())
+                }
+                _ => None,
+            })
+            .collect();
+        assert_eq!(transfers.len(), 2);
+        assert!(
+            transfers[0].contains(r#""receiver_id":"alice.testnet","amount":"90454545454545455""#)
+        );
+        assert!(transfers[1].contains(r#""receiver_id":"bob.testnet","amount":"454545454545454""#));
+        assert!(function_call_args("swap_split_callback")
+            .contains(r#""platform_amount":454545454545454"#));
+    }
+
+    #[test]
+    #[should_panic(expected = "Platform fee cannot exceed 500 bps.")]
+    fn test_platform_fee_is_capped() {
+        let mut amm = funded_amm();
+        swap_one_token_a(
+            &mut amm,
+            r#"{"platform_fee_bps": 501, "platform_account": "bob.testnet"}"#,
+        );
+    }
+
+    #[test]
+    fn test_swap_split_callback() {
+        let mut amm = funded_amm();
 
-        // balances do not change after swap (we can't test balance updates with unit tests - see integration tests)
-        // this happens because of the cross contract call
+        // the platform transfer failed, its share stays in the pool
+        amm.apply_swap(0, 100_000_000, 90_909_090_909_090_909, 0);
+        assert_refunded(
+            amm.swap_split_callback(
+                alice(),
+                0,
+                100_000_000,
+                0,
+                90_909_090_909_090_909,
+                0,
+                454_545_454_545_454,
+                Ok(()),
+                Err(PromiseError::Failed),
+            ),
+            0,
+        );
         assert_eq!(
             amm.get_balance(token_a()),
-            near_sdk::json_types::U128(1_000_000_000)
+            near_sdk::json_types::U128(1_100_000_000)
         );
         assert_eq!(
             amm.get_balance(token_b()),
-            near_sdk::json_types::U128(1_000_000_000_000_000_000)
+            near_sdk::json_types::U128(909_545_454_545_454_545)
         );
 
-        // owner deposits more token_b
-        testing_env!(get_owner_ft_transfer_context(owner(), token_b(), false));
-        amm.ft_on_transfer(
-            owner(),
-            near_sdk::json_types::U128(1_000_000_000_000_000_000),
-            "".to_string(),
+        // the sender transfer failed, the input for the sender's share is refunded
+        let mut amm = funded_amm();
+        amm.apply_swap(0, 100_000_000, 90_909_090_909_090_909, 0);
+        assert_refunded(
+            amm.swap_split_callback(
+                alice(),
+                0,
+                100_000_000,
+                0,
+                90_909_090_909_090_909,
+                0,
+                454_545_454_545_454,
+                Err(PromiseError::Failed),
+                Ok(()),
+            ),
+            99_500_000,
+        );
+        assert_eq!(
+            amm.get_balance(token_a()),
+            near_sdk::json_types::U128(1_000_500_000)
         );
         assert_eq!(
             amm.get_balance(token_b()),
-            near_sdk::json_types::U128(2_000_000_000_000_000_000)
+            near_sdk::json_types::U128(999_545_454_545_454_546)
         );
+    }
 
-        // ratio gets updated accordingly
-        assert_eq!(amm.get_ratio(), near_sdk::json_types::U128(2000));
+    #[test]
+    fn test_failed_transfers_pause_output() {
+        let mut amm = funded_amm();
+        testing_env!(get_owner_ft_transfer_context(owner(), owner(), false));
+        amm.set_max_transfer_failures(Some(2));
+
+        let failed_swap = |amm: &mut AMM, call_result| {
+            amm.swap_callback(
+                alice(),
+                0,
+                100_000_000,
+                0,
+                90_909_090_909_090_909,
+                0,
+                call_result,
+            )
+        };
+
+        let registered = || {
+            Ok(Some(StorageBalance {
+                total: U128(1_250_000_000_000_000_000_000),
+                available: U128(0),
+            }))
+        };
+
+        // a successful transfer resets the count
+        failed_swap(&mut amm, Err(PromiseError::Failed));
+        assert!(function_call_args("storage_balance_of").contains(&alice().to_string()));
+        amm.transfer_failure_callback(1, registered());
+        assert_eq!(amm.get_transfer_failures(token_b()), 1);
+        failed_swap(&mut amm, Ok(()));
+        assert_eq!(amm.get_transfer_failures(token_b()), 0);
+
+        // failures towards unregistered recipients do not count
+        failed_swap(&mut amm, Err(PromiseError::Failed));
+        amm.transfer_failure_callback(1, Ok(None));
+        failed_swap(&mut amm, Err(PromiseError::Failed));
+        amm.transfer_failure_callback(1, Ok(None));
+        assert_eq!(amm.get_transfer_failures(token_b()), 0);
+        assert!(!amm.is_output_paused(token_b()));
+
+        failed_swap(&mut amm, Err(PromiseError::Failed));
+        amm.transfer_failure_callback(1, registered());
+        failed_swap(&mut amm, Err(PromiseError::Failed));
+        amm.transfer_failure_callback(1, Err(PromiseError::Failed));
+        assert!(amm.is_output_paused(token_b()));
+        assert!(!amm.is_output_paused(token_a()));
+        assert_eq!(get_events().last().unwrap()["event"], "output_paused");
+
+        amm.resume_output(token_b());
+        assert!(!amm.is_output_paused(token_b()));
+        assert_eq!(amm.get_transfer_failures(token_b()), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Swaps into this token are paused.")]
+    fn test_swap_into_paused_output_fails() {
+        let mut amm = funded_amm();
+        amm.tokens[1].output_paused = true;
+        swap_one_token_a(&mut amm, "");
+    }
+
+    #[test]
+    fn test_reconcile_pauses_swaps_on_drift() {
+        let mut amm = funded_amm();
+        testing_env!(get_owner_ft_transfer_context(owner(), owner(), false));
+        amm.set_max_drift_bps(Some(100));
+
+        // surplus and a 1% shortfall are tolerated
+        assert!(amm.reconcile_callback(
+            Ok(near_sdk::json_types::U128(2_000_000_000)),
+            Ok(near_sdk::json_types::U128(990_000_000_000_000_000)),
+        ));
+        assert!(!amm.get_swaps_paused());
+
+        assert!(!amm.reconcile_callback(
+            Ok(near_sdk::json_types::U128(989_999_999)),
+            Ok(near_sdk::json_types::U128(1_000_000_000_000_000_000)),
+        ));
+        assert!(amm.get_swaps_paused());
+
+        amm.resume_swaps();
+        assert_swapped(swap_one_token_a(&mut amm, ""));
+    }
+
+    #[test]
+    fn test_balance_cache() {
+        let mut amm = funded_amm();
+        testing_env!(VMContextBuilder::new().block_timestamp(1_000).build());
+        amm.reconcile_callback(
+            Ok(near_sdk::json_types::U128(2_000_000_000)),
+            Ok(near_sdk::json_types::U128(1_000_000_000_000_000_000)),
+        );
+        assert_eq!(
+            amm.get_cached_balance(token_a()),
+            Some((near_sdk::json_types::U128(2_000_000_000), 1_000))
+        );
+
+        // a fresh cache answers without querying the tokens
+        testing_env!(VMContextBuilder::new()
+            .block_timestamp(1_000 + BALANCE_CACHE_TTL)
+            .build());
+        assert!(matches!(amm.reconcile(), PromiseOrValue::Value(true)));
+        testing_env!(VMContextBuilder::new()
+            .block_timestamp(1_001 + BALANCE_CACHE_TTL)
+            .build());
+        assert!(matches!(amm.reconcile(), PromiseOrValue::Promise(_)));
+
+        // a priced swap moves the reserves and sends tokens, so it clears the cache
+        amm.cache_balances(2_000_000_000, 1_000_000_000_000_000_000);
+        swap_one_token_a(&mut amm, "");
+        assert!(amm.get_cached_balance(token_a()).is_none());
+
+        amm.cache_balances(2_000_000_000, 1_000_000_000_000_000_000);
+        amm.protocol_fees[0] = 10;
+        testing_env!(get_owner_ft_transfer_context(owner(), owner(), false));
+        amm.withdraw_protocol_fees(token_a());
+        assert!(amm.get_cached_balance(token_a()).is_none());
+    }
+
+    #[test]
+    fn test_emergency_pause() {
+        let mut amm = funded_amm();
+        testing_env!(get_owner_ft_transfer_context(owner(), owner(), false));
+        amm.set_paused(true);
+        assert!(amm.is_paused());
+
+        // swaps are refunded in full, owner deposits still go through
+        assert_refunded(swap_one_token_a(&mut amm, ""), 100_000_000);
+        assert_eq!(amm.get_balance(token_a()).0, 1_000_000_000);
+        testing_env!(get_owner_ft_transfer_context(owner(), token_a(), false));
+        assert_refunded(
+            amm.ft_on_transfer(
+                owner(),
+                near_sdk::json_types::U128(100_000_000),
+                "".to_string(),
+            ),
+            0,
+        );
+        assert_eq!(amm.get_balance(token_a()).0, 1_100_000_000);
+
+        // liquidity deposits are refunded too, since the owner could sweep them
+        add_liquidity_with_refund(&mut amm, bob(), token_a(), 100_000_000, 100_000_000);
+        assert_eq!(amm.get_pending_deposit(bob()).0 .0, 0);
+        assert_eq!(amm.pending_totals, [0, 0]);
+        assert_refunded(
+            swap_one_token_a(&mut amm, r#"{"forward_msg": "add_liquidity"}"#),
+            100_000_000,
+        );
+
+        testing_env!(get_owner_ft_transfer_context(owner(), owner(), false));
+        amm.set_paused(false);
+        assert_swapped(swap_one_token_a(&mut amm, ""));
+    }
+
+    #[test]
+    fn test_inactivity_pause() {
+        let mut amm = funded_amm();
+        let mut context = VMContextBuilder::new();
+        testing_env!(context
+            .predecessor_account_id(owner())
+            .block_index(100)
+            .build());
+        amm.set_inactivity_pause_blocks(Some(50));
+        assert_eq!(amm.get_blocks_until_inactivity_pause(), Some(50));
+
+        // swaps inside the window go through
+        testing_env!(context
+            .predecessor_account_id(token_a())
+            .signer_account_id(alice())
+            .block_index(149)
+            .build());
+        assert_eq!(amm.get_blocks_until_inactivity_pause(), Some(1));
+        assert_swapped(amm.ft_on_transfer(
+            alice(),
+            near_sdk::json_types::U128(100_000_000),
+            "".to_string(),
+        ));
+
+        // the first swap after the window pauses the pool and is refunded
+        testing_env!(context.block_index(150).build());
+        assert_eq!(amm.get_blocks_until_inactivity_pause(), Some(0));
+        assert_refunded(
+            amm.ft_on_transfer(
+                alice(),
+                near_sdk::json_types::U128(100_000_000),
+                "".to_string(),
+            ),
+            100_000_000,
+        );
+        assert!(amm.is_paused());
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the owner can call this method.")]
+    fn test_set_paused_not_owner() {
+        let mut amm = funded_amm();
+        testing_env!(get_owner_ft_transfer_context(alice(), alice(), false));
+        amm.set_paused(true);
+    }
+
+    #[test]
+    #[should_panic(expected = "Swaps are paused.")]
+    fn test_swap_fails_while_paused() {
+        let mut amm = funded_amm();
+        testing_env!(get_owner_ft_transfer_context(owner(), owner(), false));
+        amm.set_max_drift_bps(Some(0));
+        amm.reconcile_callback(
+            Ok(near_sdk::json_types::U128(999_999_999)),
+            Ok(near_sdk::json_types::U128(1_000_000_000_000_000_000)),
+        );
+
+        swap_one_token_a(&mut amm, "");
+    }
+
+    #[test]
+    fn test_add_proportional() {
+        let mut amm = funded_amm();
+        testing_env!(get_owner_ft_transfer_context(owner(), owner(), false));
+
+        // exact and within 0.1% of the 1:1e9 pool ratio
+        amm.add_proportional(
+            near_sdk::json_types::U128(1_000),
+            near_sdk::json_types::U128(1_000_000_000_000),
+        );
+        amm.add_proportional(
+            near_sdk::json_types::U128(1_000),
+            near_sdk::json_types::U128(1_000_900_000_000),
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Amounts do not match the pool ratio.")]
+    fn test_add_proportional_rejects_off_ratio() {
+        let mut amm = funded_amm();
+        testing_env!(get_owner_ft_transfer_context(owner(), owner(), false));
+        amm.add_proportional(
+            near_sdk::json_types::U128(1_000),
+            near_sdk::json_types::U128(1_002_000_000_000),
+        );
     }
 
     #[test]
     fn test_overflow_in_swap_and_ratio() {
-        let mut amm = AMM::new(owner(), token_a(), token_b());
+        let mut amm = AMM::new(owner(), token_a(), token_b(), None);
 
         // Large u128 number to test for overflows
         const TEST_AMOUNT: U128 = near_sdk::json_types::U128(u128::MAX / 2);
@@ -447,7 +6108,7 @@ mod tests {
         // ratio gets updated accordingly and calculation does not overflow
         assert_eq!(
             amm.get_ratio(),
-            near_sdk::json_types::U128(289_480_223_093_290_488_503_844_922_296_628_310_727)
+            near_sdk::json_types::U128(289_480_223_093_290_488_558_927_462_521_719_769_629)
         );
 
         // swap calculation does not overflow
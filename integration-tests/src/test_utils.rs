@@ -25,6 +25,18 @@ pub async fn register_with_token(
     Ok(())
 }
 
+pub async fn register_with_both_tokens(
+    caller: &Account,
+    registree: &AccountId,
+    token_a: &Contract,
+    token_b: &Contract,
+) -> Result<()> {
+    register_with_token(caller, registree, token_a).await?;
+    register_with_token(caller, registree, token_b).await?;
+
+    Ok(())
+}
+
 pub async fn check_ratio_value(
     amm_contract: &Contract,
     caller: &Account,
@@ -48,12 +60,11 @@ pub async fn check_ratio_value(
     Ok(ratio == expected_ratio)
 }
 
-pub async fn check_amm_balance_value(
+pub async fn get_amm_balance(
     amm_contract: &Contract,
     caller: &Account,
-    expected_balance: u128,
     token: &Contract,
-) -> Result<bool> {
+) -> Result<u128> {
     let call_result = caller
         .call(amm_contract.id(), "get_balance")
         .args_json(json!({ "token": token.id() }))
@@ -69,13 +80,20 @@ pub async fn check_amm_balance_value(
         .json::<U128>()?
         .into();
 
-    Ok(balance == expected_balance)
+    Ok(balance)
 }
-pub async fn check_user_balance_value(
-    token: &Contract,
+
+pub async fn check_amm_balance_value(
+    amm_contract: &Contract,
     caller: &Account,
     expected_balance: u128,
+    token: &Contract,
 ) -> Result<bool> {
+    let balance = get_amm_balance(amm_contract, caller, token).await?;
+
+    Ok(balance == expected_balance)
+}
+pub async fn get_user_balance(token: &Contract, caller: &Account) -> Result<u128> {
     let call_result = caller
         .call(token.id(), "ft_balance_of")
         .args_json(json!({ "account_id": caller.id() }))
@@ -91,6 +109,16 @@ pub async fn check_user_balance_value(
         .json::<U128>()?
         .into();
 
+    Ok(balance)
+}
+
+pub async fn check_user_balance_value(
+    token: &Contract,
+    caller: &Account,
+    expected_balance: u128,
+) -> Result<bool> {
+    let balance = get_user_balance(token, caller).await?;
+
     Ok(balance == expected_balance)
 }
 
@@ -117,10 +145,20 @@ pub async fn transfer_tokens_to_amm(
     token: &Contract,
     amm_contract: &Contract,
     amount: String,
+) -> Result<()> {
+    transfer_tokens_to_amm_with_msg(caller, token, amm_contract, amount, "".to_string()).await
+}
+
+pub async fn transfer_tokens_to_amm_with_msg(
+    caller: &Account,
+    token: &Contract,
+    amm_contract: &Contract,
+    amount: String,
+    msg: String,
 ) -> Result<()> {
     let transfer = caller
         .call(token.id(), "ft_transfer_call")
-        .args_json(json!({"receiver_id": amm_contract.id(), "amount": amount, "msg": ""}))
+        .args_json(json!({"receiver_id": amm_contract.id(), "amount": amount, "msg": msg}))
         .deposit(1)
         .max_gas()
         .transact()
@@ -135,3 +173,26 @@ pub async fn transfer_tokens_to_amm(
 
     Ok(())
 }
+
+pub async fn get_fee_bps(amm_contract: &Contract, caller: &Account) -> Result<u128> {
+    let call_result = caller
+        .call(amm_contract.id(), "get_fee_bps")
+        .max_gas()
+        .transact()
+        .await?;
+    assert!(call_result.is_success(), "Failed to retrieve the AMM fee.");
+
+    let fee_bps: u16 = call_result.json()?;
+    Ok(u128::from(fee_bps))
+}
+
+// Mirrors the contract: the fee is taken from the input before the constant-product formula
+pub fn compute_amount_out(
+    balance_in: u128,
+    balance_out: u128,
+    amount_in: u128,
+    fee_bps: u128,
+) -> u128 {
+    let amount_in_after_fee = amount_in * (10000 - fee_bps) / 10000;
+    balance_out * amount_in_after_fee / (balance_in + amount_in_after_fee)
+}
@@ -1,16 +1,23 @@
+use near_contract_standards::fungible_token::core::FungibleTokenCore;
 use near_contract_standards::fungible_token::metadata::{
     FungibleTokenMetadata, FungibleTokenMetadataProvider,
 };
+use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
+use near_contract_standards::fungible_token::resolver::FungibleTokenResolver;
 use near_contract_standards::fungible_token::FungibleToken;
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
 use near_sdk::json_types::U128;
-use near_sdk::{near_bindgen, AccountId, PanicOnDefault, PromiseOrValue};
+use near_sdk::{env, near_bindgen, require, AccountId, PanicOnDefault, PromiseOrValue};
 
 #[near_bindgen]
 #[derive(BorshSerialize, BorshDeserialize, PanicOnDefault)]
 pub struct Contract {
     token: FungibleToken,
-    metadata: FungibleTokenMetadata
+    metadata: FungibleTokenMetadata,
+    // (token, sender, amount, msg) of the last transfer received through ft_transfer_call
+    last_received: Option<(AccountId, AccountId, U128, String)>,
+    // Makes every transfer fail, like a partner token that froze transfers
+    transfers_paused: bool,
 }
 
 #[near_bindgen]
@@ -26,8 +33,10 @@ impl Contract {
                 icon: None,
                 reference: None,
                 reference_hash: None,
-                decimals: decimals,
-            }
+                decimals,
+            },
+            last_received: None,
+            transfers_paused: false,
         }
     }
 
@@ -39,9 +48,62 @@ impl Contract {
     pub fn burn(&mut self, account_id: AccountId, amount: U128) {
         self.token.internal_withdraw(&account_id, amount.into());
     }
+
+    pub fn set_transfers_paused(&mut self, transfers_paused: bool) {
+        self.transfers_paused = transfers_paused;
+    }
+
+    pub fn get_last_received(&self) -> Option<(AccountId, AccountId, U128, String)> {
+        self.last_received.clone()
+    }
+}
+
+// Same as impl_fungible_token_core!, with transfers failing while paused
+#[near_bindgen]
+impl FungibleTokenCore for Contract {
+    #[payable]
+    fn ft_transfer(&mut self, receiver_id: AccountId, amount: U128, memo: Option<String>) {
+        require!(!self.transfers_paused, "Transfers are paused.");
+        self.token.ft_transfer(receiver_id, amount, memo)
+    }
+
+    #[payable]
+    fn ft_transfer_call(
+        &mut self,
+        receiver_id: AccountId,
+        amount: U128,
+        memo: Option<String>,
+        msg: String,
+    ) -> PromiseOrValue<U128> {
+        require!(!self.transfers_paused, "Transfers are paused.");
+        self.token.ft_transfer_call(receiver_id, amount, memo, msg)
+    }
+
+    fn ft_total_supply(&self) -> U128 {
+        self.token.ft_total_supply()
+    }
+
+    fn ft_balance_of(&self, account_id: AccountId) -> U128 {
+        self.token.ft_balance_of(account_id)
+    }
+}
+
+#[near_bindgen]
+impl FungibleTokenResolver for Contract {
+    #[private]
+    fn ft_resolve_transfer(
+        &mut self,
+        sender_id: AccountId,
+        receiver_id: AccountId,
+        amount: U128,
+    ) -> U128 {
+        let (used_amount, _) =
+            self.token
+                .internal_ft_resolve_transfer(&sender_id, receiver_id, amount);
+        used_amount.into()
+    }
 }
 
-near_contract_standards::impl_fungible_token_core!(Contract, token);
 near_contract_standards::impl_fungible_token_storage!(Contract, token);
 
 #[near_bindgen]
@@ -50,3 +112,17 @@ impl FungibleTokenMetadataProvider for Contract {
         self.metadata.clone()
     }
 }
+
+// Lets the test token act as the target of a forwarded swap. It keeps every received token.
+#[near_bindgen]
+impl FungibleTokenReceiver for Contract {
+    fn ft_on_transfer(
+        &mut self,
+        sender_id: AccountId,
+        amount: U128,
+        msg: String,
+    ) -> PromiseOrValue<U128> {
+        self.last_received = Some((env::predecessor_account_id(), sender_id, amount, msg));
+        PromiseOrValue::Value(U128(0))
+    }
+}